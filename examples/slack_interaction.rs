@@ -0,0 +1,53 @@
+//! Stands in for Slack when clicking alert buttons locally. Sends a signed
+//! `block_actions` payload to the interaction endpoint:
+//!
+//! ```sh
+//! SLACK_SIGNING_SECRET=secret cargo run --example slack_interaction -- resolve 'helved/app|no.nav.Foo|1a2b'
+//! ```
+//!
+//! Actions are `acknowledge`, `mute_1h`, `mute_24h` and `resolve`. The key is the
+//! aggregation key, which the alert buttons carry as their value. `INTERACTION_URL`
+//! overrides the target.
+
+use anyhow::{Context, Result};
+use aws_lc_rs::hmac;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .expect("failed to install rustls aws-lc-rs crypto provider");
+
+    let mut args = std::env::args().skip(1);
+    let usage = "usage: slack_interaction <action_id> <aggregation key> [user]";
+    let action_id = args.next().context(usage)?;
+    let key = args.next().context(usage)?;
+    let user = args.next().unwrap_or_else(|| "local".into());
+    let secret = std::env::var("SLACK_SIGNING_SECRET").context("SLACK_SIGNING_SECRET missing")?;
+    let url = std::env::var("INTERACTION_URL")
+        .unwrap_or_else(|_| "http://localhost:8081/slack/interactions".into());
+
+    let payload = serde_json::json!({
+        "type": "block_actions",
+        "user": { "id": "ULOCAL", "username": user },
+        "actions": [{ "action_id": action_id, "value": key, "type": "button" }],
+    });
+    let body = format!("payload={}", urlencoding::encode(&payload.to_string()));
+    let timestamp = chrono::Utc::now().timestamp().to_string();
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, format!("v0:{timestamp}:{body}").as_bytes());
+    let signature: String = tag.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+
+    let response = reqwest::Client::new()
+        .post(&url)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Slack-Request-Timestamp", &timestamp)
+        .header("X-Slack-Signature", format!("v0={signature}"))
+        .body(body)
+        .send()
+        .await
+        .context(format!("failed to post to {url}"))?;
+    println!("{} {}", response.status(), response.text().await?);
+    Ok(())
+}
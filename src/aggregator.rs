@@ -1,15 +1,30 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration as StdDuration;
 
 use chrono::{DateTime, Duration as ChronoDuration, Utc};
-use tokio::sync::Mutex;
+use serde::Serialize;
+use tokio::sync::{Mutex, MutexGuard};
 use tokio::time::Instant;
 
-use crate::model::{AlertView, Log};
-use crate::slack::{PostedMessage, Slack};
+use crate::health::Health;
+use crate::history::{ContainerActivity, History, Resolved};
+use crate::metrics::METRICS;
+use crate::model::{AlertView, Fingerprint, Log, Source, StableHasher, StackTraceView, ThreadView, Variant};
+use crate::routing::Router;
+use crate::notifier::{Notifier, PostedMessage};
+use crate::silence::{Matchers, NewSilence, Silence, Silences};
+use crate::state::{Snapshot, StateStore, StoredAggregate};
+use crate::threshold::{Rule, Thresholds};
+
+/// Distinct pods and trace ids kept per aggregate. Both are persisted with it, and an
+/// incident can produce a new trace id for every occurrence.
+const MAX_PODS: usize = 50;
+const MAX_TRACE_IDS: usize = 100;
 
 pub struct Aggregate {
+    namespace: String,
     container: String,
     first_seen: DateTime<Utc>,
     last_seen: DateTime<Utc>,
@@ -17,62 +32,512 @@ pub struct Aggregate {
     sample: Log,
     pods: HashSet<String>,
     trace_ids: HashSet<String>,
-    posted: Option<PostedMessage>,
+    /// One message per destination channel.
+    posted: Vec<PostedMessage>,
+    /// Channels whose create failed, retried on flush.
+    unposted: Vec<String>,
     last_edit: Option<Instant>,
     dirty: bool,
+    /// Hashes of every distinct raw message seen, capped at `thread_variants_max`.
+    variants: HashSet<u64>,
+    /// Distinct messages not yet posted to the thread.
+    pending_variants: Vec<Variant>,
+    /// Set while a threshold rule holds the alert back.
+    threshold: Option<Threshold>,
+    /// Occurrences held back before the alert was first posted.
+    buffered: u32,
+    /// Who acknowledged the alert from Slack.
+    acked_by: Option<String>,
+    /// Who muted the alert from Slack, and until when.
+    muted: Option<(String, DateTime<Utc>)>,
+}
+
+impl Aggregate {
+    /// How long the aggregate stays open after its last occurrence. A buffered one stays
+    /// at least as long as its threshold window, so slow repeats still add up.
+    fn open_for(&self, window: ChronoDuration) -> ChronoDuration {
+        self.threshold.as_ref().map_or(window, |t| t.window.max(window))
+    }
+}
+
+/// Occurrence times inside a threshold rule's sliding window.
+struct Threshold {
+    count: u32,
+    window: ChronoDuration,
+    seen: VecDeque<DateTime<Utc>>,
+}
+
+impl Threshold {
+    fn new(rule: &Rule) -> Self {
+        Threshold { count: rule.count, window: ChronoDuration::minutes(rule.minutes), seen: VecDeque::new() }
+    }
+
+    /// Record an occurrence. True once the window holds enough of them.
+    fn record(&mut self, at: DateTime<Utc>) -> bool {
+        self.seen.push_back(at);
+        let latest = self.seen.iter().copied().max().unwrap_or(at);
+        self.seen.retain(|t| latest.signed_duration_since(*t) <= self.window);
+        self.seen.len() as u32 >= self.count
+    }
 }
 
-pub struct Aggregator {
+pub struct Config {
+    pub cluster: String,
+    pub window_seconds: i64,
+    pub edit_throttle_ms: u64,
+    /// Max distinct messages per aggregate posted as thread replies.
+    pub thread_variants_max: usize,
+    /// Max variants batched into a single thread reply.
+    pub thread_batch_size: usize,
+    /// Stack trace fingerprinting; its in-app packages also pick the frames shown in alerts.
+    pub fingerprint: Fingerprint,
+    pub thresholds: Thresholds,
+    /// Base of the Slack permalinks in the aggregates API, e.g. `https://nav-it.slack.com`.
+    /// Without it there are no permalinks, Slack has no workspace-independent link.
+    pub slack_workspace_url: Option<String>,
+}
+
+/// What `GET /api/aggregates` shows of an aggregate.
+#[derive(Serialize, Debug)]
+pub struct AggregateSummary {
+    pub key: String,
+    pub namespace: String,
+    pub container: String,
+    pub logger: Option<String>,
+    pub level: String,
+    pub message: String,
+    pub count: u32,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub pods: Vec<String>,
+    pub trace_ids: Vec<String>,
+    pub permalinks: Vec<String>,
+    /// Changed since the Slack message was last edited.
+    pub dirty: bool,
+    /// Distinct messages waiting to be replied in the thread.
+    pub pending_variants: usize,
+    /// Milliseconds until the edit throttle lets the message be edited again.
+    pub throttled_ms: u64,
+    /// Held back by a threshold rule, not posted yet.
+    pub buffering: bool,
+    pub acked_by: Option<String>,
+}
+
+/// What `GET /api/aggregates/{key}` shows: the summary and the latest sample.
+#[derive(Serialize, Debug)]
+pub struct AggregateDetail {
+    #[serde(flatten)]
+    pub summary: AggregateSummary,
+    pub sample: Log,
+}
+
+pub struct Aggregator<N: Notifier> {
     map: Mutex<HashMap<String, Aggregate>>,
-    slack: Arc<Slack>,
+    notifier: Arc<N>,
+    router: Router,
+    thresholds: Thresholds,
+    cluster: String,
+    fingerprint: Fingerprint,
     window: ChronoDuration,
     edit_throttle: StdDuration,
+    thread_variants_max: usize,
+    thread_batch_size: usize,
+    slack_workspace_url: Option<String>,
+    silences: Mutex<Silences>,
+    /// Recent activity for the dashboard.
+    history: Mutex<History>,
+    store: Option<Box<dyn StateStore>>,
+    /// Set whenever `map` or `silences` change in a way worth persisting. Suppressed counts
+    /// alone don't set it, they are saved along with the next change, at the latest when
+    /// their silence expires.
+    changed: AtomicBool,
 }
 
-impl Aggregator {
-    pub fn new(slack: Arc<Slack>, window_seconds: i64, edit_throttle_ms: u64) -> Arc<Self> {
+impl<N: Notifier> Aggregator<N> {
+    pub fn new(
+        notifier: Arc<N>,
+        router: Router,
+        config: Config,
+        store: Option<Box<dyn StateStore>>,
+    ) -> Arc<Self> {
         Arc::new(Self {
             map: Mutex::new(HashMap::new()),
-            slack,
-            window: ChronoDuration::seconds(window_seconds),
-            edit_throttle: StdDuration::from_millis(edit_throttle_ms),
+            notifier,
+            router,
+            thresholds: config.thresholds,
+            cluster: config.cluster,
+            fingerprint: config.fingerprint,
+            window: ChronoDuration::seconds(config.window_seconds),
+            edit_throttle: StdDuration::from_millis(config.edit_throttle_ms),
+            thread_variants_max: config.thread_variants_max,
+            thread_batch_size: config.thread_batch_size.max(1),
+            slack_workspace_url: config.slack_workspace_url,
+            silences: Mutex::new(Silences::default()),
+            history: Mutex::new(History::default()),
+            store,
+            changed: AtomicBool::new(false),
         })
     }
 
+    /// Load aggregates saved by a previous run, so follow-up occurrences edit the
+    /// existing Slack messages instead of posting new ones.
+    pub async fn restore(&self) {
+        let Some(store) = &self.store else { return };
+        let snapshot = match store.load().await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                log::error!("failed to restore aggregator state: {}", e);
+                return;
+            }
+        };
+        // Aggregates that went cold while we were down are kept too, so the next flush
+        // marks their messages as resolved.
+        let mut map = self.map.lock().await;
+        for stored in snapshot.aggregates {
+            map.insert(
+                stored.key,
+                Aggregate {
+                    namespace: stored.namespace,
+                    container: stored.container,
+                    first_seen: stored.first_seen,
+                    last_seen: stored.last_seen,
+                    count: stored.count,
+                    sample: stored.sample,
+                    pods: stored.pods,
+                    trace_ids: stored.trace_ids,
+                    posted: stored.posted,
+                    unposted: stored.unposted,
+                    last_edit: None,
+                    dirty: false,
+                    variants: stored.variants,
+                    pending_variants: Vec::new(),
+                    threshold: None,
+                    buffered: stored.buffered,
+                    acked_by: stored.acked_by,
+                    muted: stored.muted,
+                },
+            );
+        }
+        log::info!("restored {} aggregates", map.len());
+
+        let mut silences = self.silences.lock().await;
+        for silence in snapshot.silences {
+            let id = silence.id.clone();
+            if let Err(e) = silences.restore(silence) {
+                log::warn!("dropping stored silence {}: {}", id, e);
+            }
+        }
+    }
+
+    pub async fn add_silence(&self, new: NewSilence) -> anyhow::Result<Silence> {
+        let silence = self.silences.lock().await.add(new, Utc::now())?;
+        log::info!("silence {} created by {}: {:?}", silence.id, silence.created_by, silence.matchers);
+        self.changed.store(true, Ordering::Relaxed);
+        Ok(silence)
+    }
+
+    pub async fn expire_silence(&self, id: &str) -> bool {
+        let expired = self.silences.lock().await.expire(id);
+        if expired {
+            log::info!("silence {} expired", id);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+        expired
+    }
+
+    pub async fn silences(&self) -> Vec<Silence> {
+        self.silences.lock().await.list(Utc::now())
+    }
+
+    /// Aggregates currently open, including buffered ones.
+    pub async fn active(&self) -> usize {
+        self.map.lock().await.len()
+    }
+
+    /// Every open aggregate, most recently seen first.
+    pub async fn summaries(&self) -> Vec<AggregateSummary> {
+        let now = Instant::now();
+        let map = self.map.lock().await;
+        let mut summaries: Vec<AggregateSummary> =
+            map.iter().map(|(key, agg)| self.summary(key, agg, now)).collect();
+        summaries.sort_by_key(|s| std::cmp::Reverse(s.last_seen));
+        summaries
+    }
+
+    pub async fn detail(&self, key: &str) -> Option<AggregateDetail> {
+        let map = self.map.lock().await;
+        let agg = map.get(key)?;
+        Some(AggregateDetail { summary: self.summary(key, agg, Instant::now()), sample: agg.sample.clone() })
+    }
+
+    /// Errors per minute for every container that had any in the last hour.
+    pub async fn activity(&self) -> Vec<ContainerActivity> {
+        self.history.lock().await.activity(Utc::now())
+    }
+
+    /// Aggregates that closed lately, newest first.
+    pub async fn recently_resolved(&self) -> Vec<Resolved> {
+        self.history.lock().await.recently_resolved()
+    }
+
+    fn resolved_entry(&self, key: &str, agg: &Aggregate, by: Option<&str>) -> Resolved {
+        Resolved {
+            key: key.to_string(),
+            namespace: agg.namespace.clone(),
+            container: agg.container.clone(),
+            logger: agg.sample.logger_name().map(|s| s.to_string()),
+            message: agg.sample.message().to_string(),
+            count: agg.count,
+            first_seen: agg.first_seen,
+            last_seen: agg.last_seen,
+            resolved_at: Utc::now(),
+            resolved_by: by.map(|s| s.to_string()),
+            permalinks: self.permalinks(&agg.posted),
+        }
+    }
+
+    fn permalinks(&self, posted: &[PostedMessage]) -> Vec<String> {
+        match &self.slack_workspace_url {
+            Some(url) => posted.iter().map(|m| m.permalink(url)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn summary(&self, key: &str, agg: &Aggregate, now: Instant) -> AggregateSummary {
+        let sorted = |set: &HashSet<String>| {
+            let mut values: Vec<String> = set.iter().cloned().collect();
+            values.sort();
+            values
+        };
+        let throttled = agg
+            .last_edit
+            .map(|t| self.edit_throttle.saturating_sub(now.saturating_duration_since(t)))
+            .unwrap_or_default();
+        AggregateSummary {
+            key: key.to_string(),
+            namespace: agg.namespace.clone(),
+            container: agg.container.clone(),
+            logger: agg.sample.logger_name().map(|s| s.to_string()),
+            level: agg.sample.level().to_string(),
+            message: agg.sample.message().to_string(),
+            count: agg.count,
+            first_seen: agg.first_seen,
+            last_seen: agg.last_seen,
+            pods: sorted(&agg.pods),
+            trace_ids: sorted(&agg.trace_ids),
+            permalinks: self.permalinks(&agg.posted),
+            dirty: agg.dirty,
+            pending_variants: agg.pending_variants.len(),
+            throttled_ms: throttled.as_millis() as u64,
+            buffering: agg.threshold.is_some(),
+            acked_by: agg.acked_by.clone(),
+        }
+    }
+
+    /// Note on the alert who acknowledged it. False if there is no such posted aggregate.
+    pub async fn acknowledge(&self, key: &str, user: &str) -> bool {
+        let mut map = self.map.lock().await;
+        let Some(agg) = map.get_mut(key).filter(|agg| !agg.posted.is_empty()) else { return false };
+        agg.acked_by = Some(user.to_string());
+        agg.dirty = true;
+        self.changed.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// Silence an aggregate's key for a while and note it on the alert. False if there
+    /// is no such posted aggregate.
+    pub async fn mute(&self, key: &str, user: &str, minutes: i64) -> anyhow::Result<bool> {
+        let mut map = self.map.lock().await;
+        let Some(agg) = map.get_mut(key).filter(|agg| !agg.posted.is_empty()) else { return Ok(false) };
+        let silence = self.add_silence(NewSilence {
+            matchers: Matchers { key: Some(key.to_string()), ..Matchers::default() },
+            created_by: user.to_string(),
+            comment: "muted from Slack".into(),
+            expires_at: None,
+            duration_minutes: Some(minutes),
+        })
+        .await?;
+        agg.muted = Some((user.to_string(), silence.expires_at));
+        agg.dirty = true;
+        Ok(true)
+    }
+
+    /// Close an aggregate now instead of waiting for it to go cold. The next occurrence
+    /// posts a new alert. False if there is no such posted aggregate.
+    pub async fn resolve(&self, key: &str, user: &str) -> bool {
+        let agg = {
+            let mut map = self.map.lock().await;
+            if map.get(key).is_none_or(|agg| agg.posted.is_empty()) {
+                return false;
+            }
+            map.remove(key).expect("checked above")
+        };
+        self.close(key, agg, Some(user)).await;
+        true
+    }
+
+    /// Mark an aggregate that was taken out of the map as resolved, in Slack and in the
+    /// history. `by` is unset when it went cold.
+    async fn close(&self, key: &str, agg: Aggregate, by: Option<&str>) {
+        METRICS.aggregate_closed(key);
+        self.changed.store(true, Ordering::Relaxed);
+        // Buffered ones were never posted, so there is nothing to show as resolved.
+        if agg.posted.is_empty() {
+            return;
+        }
+        self.history.lock().await.resolved(self.resolved_entry(key, &agg, by));
+        let view = AlertView {
+            resolved_after: Some(Utc::now().signed_duration_since(agg.last_seen)),
+            resolved_by: by,
+            ..self.view(key, &agg)
+        };
+        let (blocks, fallback) = (view.to_blocks(), view.fallback_text());
+        for message in &agg.posted {
+            if let Err(e) = self.notifier.resolve(message, blocks.clone(), &fallback).await {
+                log::warn!("resolve failed for key {}: {}", key, e);
+            }
+        }
+    }
+
+    /// Spawn the periodic persist task. Saves a snapshot whenever the aggregates changed.
+    pub fn spawn_persist(self: Arc<Self>, every: StdDuration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(every);
+            loop {
+                ticker.tick().await;
+                self.persist().await;
+            }
+        })
+    }
+
+    /// Save a snapshot if anything changed since the last one.
+    pub async fn persist(&self) {
+        let Some(store) = &self.store else { return };
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let silences = self.silences().await;
+        let snapshot = {
+            let map = self.map.lock().await;
+            Snapshot {
+                silences,
+                // Buffered aggregates have nothing in Slack to come back to.
+                aggregates: map
+                    .iter()
+                    .filter(|(_, agg)| agg.threshold.is_none())
+                    .map(|(key, agg)| StoredAggregate {
+                        key: key.clone(),
+                        namespace: agg.namespace.clone(),
+                        container: agg.container.clone(),
+                        count: agg.count,
+                        first_seen: agg.first_seen,
+                        last_seen: agg.last_seen,
+                        sample: agg.sample.clone(),
+                        pods: agg.pods.clone(),
+                        trace_ids: agg.trace_ids.clone(),
+                        posted: agg.posted.clone(),
+                        unposted: agg.unposted.clone(),
+                        variants: agg.variants.clone(),
+                        buffered: agg.buffered,
+                        acked_by: agg.acked_by.clone(),
+                        muted: agg.muted.clone(),
+                    })
+                    .collect(),
+            }
+        };
+        if let Err(e) = store.save(&snapshot).await {
+            log::warn!("failed to persist aggregator state: {}", e);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
     /// Spawn the periodic flush task. Tick edits throttled aggregates and evicts cold ones.
-    pub fn spawn_flush(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+    pub fn spawn_flush(self: Arc<Self>, health: Arc<Health>) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
+            let task = health.task("flush");
             let mut ticker = tokio::time::interval(StdDuration::from_secs(1));
             loop {
                 ticker.tick().await;
-                self.flush_tick().await;
+                task.run(self.flush_tick()).await;
             }
         })
     }
 
-    pub async fn ingest(&self, log: Log, container: String, pod: String) {
-        let key = log.aggregation_key(&container);
+    pub async fn ingest(&self, log: Log, source: Source) {
+        let pod = source.pod_label();
+        // A spike counts the lines of every pod of the container.
+        let pods: Vec<String> = match log.spike() {
+            Some(spike) if !spike.pods.is_empty() => spike.pods.clone(),
+            _ => vec![pod.clone()],
+        };
+        let Source { namespace, container, .. } = source;
+        let key = log.aggregation_key(&namespace, &container, &self.fingerprint);
         let now = Utc::now();
         let event_ts = log.parsed_timestamp().unwrap_or(now);
         let trace = log.trace_id().map(|s| s.to_string());
+        self.history.lock().await.record(&namespace, &container, now);
+
+        let silenced = self.silences.lock().await.suppress(&key, &namespace, &container, &log, now);
+        if silenced.is_some() {
+            // An alert that was already posted stays open while muted instead of resolving,
+            // and shows the full count once something unmuted edits it.
+            let mut map = self.map.lock().await;
+            if let Some(agg) = map.get_mut(&key)
+                && now.signed_duration_since(agg.last_seen) < agg.open_for(self.window)
+            {
+                agg.count += 1;
+                agg.last_seen = agg.last_seen.max(event_ts).max(now);
+                METRICS.error_ingested(&key);
+            }
+            return;
+        }
 
         // Decide path under lock; do slack IO afterwards (or via flush task).
+        METRICS.error_ingested(&key);
         let mut map = self.map.lock().await;
 
         if let Some(agg) = map.get_mut(&key) {
-            if now.signed_duration_since(agg.last_seen) < self.window {
+            if now.signed_duration_since(agg.last_seen) < agg.open_for(self.window) {
                 agg.count += 1;
                 agg.last_seen = agg.last_seen.max(event_ts).max(now);
-                agg.pods.insert(pod);
-                if let Some(t) = trace {
-                    agg.trace_ids.insert(t);
+                for pod in &pods {
+                    if agg.pods.len() < MAX_PODS {
+                        agg.pods.insert(pod.clone());
+                    }
+                }
+                if let Some(t) = &trace
+                    && agg.trace_ids.len() < MAX_TRACE_IDS
+                {
+                    agg.trace_ids.insert(t.clone());
+                }
+                if agg.variants.len() < self.thread_variants_max
+                    && agg.variants.insert(message_hash(log.message()))
+                {
+                    agg.pending_variants.push(Variant {
+                        message: log.message().to_string(),
+                        pod,
+                        trace_id: trace,
+                        seen: event_ts,
+                    });
                 }
                 agg.sample = log;
+                if let Some(threshold) = agg.threshold.as_mut() {
+                    if !threshold.record(event_ts.min(now)) {
+                        return;
+                    }
+                    // Crossed the threshold: post it as if it were new.
+                    agg.threshold = None;
+                    agg.buffered = agg.count;
+                    self.post(key, map).await;
+                    return;
+                }
                 agg.dirty = true;
+                self.changed.store(true, Ordering::Relaxed);
                 return;
             }
-            // Stale: evict and fall through to fresh post.
-            map.remove(&key);
+            // Stale: close it the way a flush would have, then start a fresh one.
+            let stale = map.remove(&key).expect("matched above");
+            self.close(&key, stale, None).await;
         }
 
         // Fresh aggregate. Insert placeholder first so concurrent ingests merge.
@@ -80,67 +545,158 @@ impl Aggregator {
         if let Some(t) = trace {
             trace_ids.insert(t);
         }
-        let mut pods = HashSet::new();
-        pods.insert(pod);
+        let pods: HashSet<String> = pods.into_iter().take(MAX_PODS).collect();
 
+        let first_variant = message_hash(log.message());
+        let mut threshold = self
+            .thresholds
+            .rule_for(&namespace, &container, &log)
+            .map(Threshold::new);
+        let buffer = threshold.as_mut().is_some_and(|t| !t.record(event_ts.min(now)));
         let agg = Aggregate {
-            container: container.clone(),
+            namespace,
+            container,
             first_seen: event_ts.min(now),
             last_seen: event_ts.max(now),
             count: 1,
             sample: log,
             pods,
             trace_ids,
-            posted: None,
+            posted: Vec::new(),
+            unposted: Vec::new(),
             last_edit: None,
             dirty: false,
+            // The first message is already in the alert itself.
+            variants: HashSet::from([first_variant]),
+            pending_variants: Vec::new(),
+            threshold: if buffer { threshold } else { None },
+            buffered: 0,
+            acked_by: None,
+            muted: None,
         };
         map.insert(key.clone(), agg);
+        if buffer {
+            return;
+        }
+        self.post(key, map).await;
+    }
 
-        // Build view + post while still holding the lock so we don't double-post for the
-        // same key on bursts. Volume is low so this is acceptable.
-        let view = {
-            let agg = map.get(&key).expect("just inserted");
-            build_view(agg)
+    /// Create the alert for an aggregate in every channel it routes to. Holds the lock
+    /// while building the view so bursts on the same key don't double-post; volume is
+    /// low so this is acceptable.
+    async fn post(&self, key: String, mut map: MutexGuard<'_, HashMap<String, Aggregate>>) {
+        let (channels, blocks, fallback, stack_trace) = {
+            let Some(agg) = map.get_mut(&key) else { return };
+            // Anything buffered so far is summed up in the alert, not replied one by one.
+            agg.pending_variants.clear();
+            agg.dirty = false;
+            let agg = &*agg;
+            let view = self.view(&key, agg);
+            (
+                self.router.channels(&agg.namespace, &agg.container, &agg.sample),
+                view.to_blocks(),
+                view.fallback_text(),
+                view.sample.stack_trace().map(|t| t.to_string()),
+            )
         };
-        let blocks = view.to_blocks();
-        let fallback = view.fallback_text();
         drop(map);
 
-        match self.slack.post(blocks, &fallback).await {
-            Ok(posted) => {
-                let mut map = self.map.lock().await;
-                if let Some(agg) = map.get_mut(&key) {
-                    agg.posted = Some(posted);
-                    agg.last_edit = Some(Instant::now());
+        let (posted, unposted) = self.create(&key, &channels, &blocks, &fallback, stack_trace.as_deref()).await;
+
+        let mut map = self.map.lock().await;
+        if posted.is_empty() {
+            // Drop the aggregate so the next event tries again.
+            map.remove(&key);
+            return;
+        }
+        if let Some(agg) = map.get_mut(&key) {
+            agg.posted = posted;
+            agg.unposted = unposted;
+            agg.last_edit = Some(Instant::now());
+        }
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Create the alert in each of `channels`, with the full stack trace in its thread.
+    /// Returns the messages created and the channels that failed.
+    async fn create(
+        &self,
+        key: &str,
+        channels: &[String],
+        blocks: &serde_json::Value,
+        fallback: &str,
+        stack_trace: Option<&str>,
+    ) -> (Vec<PostedMessage>, Vec<String>) {
+        let mut posted = Vec::new();
+        let mut failed = Vec::new();
+        for channel in channels {
+            match self.notifier.create(channel, blocks.clone(), fallback).await {
+                Ok(message) => posted.push(message),
+                Err(e) => {
+                    log::error!("slack post to {} failed for key {}: {}", channel, key, e);
+                    failed.push(channel.clone());
                 }
             }
-            Err(e) => {
-                log::error!("slack post failed for key {}: {}", key, e);
-                // Drop the aggregate so the next event tries again.
-                let mut map = self.map.lock().await;
-                map.remove(&key);
+        }
+
+        // The alert only shows the top frames; the full trace goes in the thread.
+        if let Some(trace) = stack_trace {
+            let view = StackTraceView { trace };
+            let (blocks, fallback) = (view.to_blocks(), view.fallback_text());
+            for message in &posted {
+                if let Err(e) = self.notifier.reply(message, blocks.clone(), &fallback).await {
+                    log::warn!("stack trace reply failed for key {}: {}", key, e);
+                }
             }
         }
+        (posted, failed)
+    }
+
+    fn view<'a>(&'a self, key: &'a str, agg: &'a Aggregate) -> AlertView<'a> {
+        AlertView {
+            sample: &agg.sample,
+            cluster: &self.cluster,
+            namespace: &agg.namespace,
+            container: &agg.container,
+            count: agg.count,
+            first_seen: agg.first_seen,
+            last_seen: agg.last_seen,
+            pods: &agg.pods,
+            trace_ids: &agg.trace_ids,
+            key,
+            buffered: agg.buffered,
+            acked_by: agg.acked_by.as_deref(),
+            // The silence may have been expired early; that is not tracked here.
+            muted: agg.muted.as_ref().filter(|(_, until)| *until > Utc::now()).map(|(by, until)| (by.as_str(), *until)),
+            resolved_after: None,
+            resolved_by: None,
+            in_app: &self.fingerprint.in_app,
+        }
     }
 
     async fn flush_tick(&self) {
         let now = Utc::now();
         let now_inst = Instant::now();
 
+        if self.silences.lock().await.prune(now) {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+
         // Collect work without holding lock across slack calls.
-        let mut to_update: Vec<(String, serde_json::Value, String, PostedMessage)> = Vec::new();
+        let mut to_create: Vec<(String, Vec<String>)> = Vec::new();
+        let mut to_update: Vec<(String, serde_json::Value, String, Vec<PostedMessage>)> = Vec::new();
+        let mut to_reply: Vec<(String, Vec<Variant>, Vec<PostedMessage>)> = Vec::new();
         let mut to_evict: Vec<String> = Vec::new();
 
         {
-            let map = self.map.lock().await;
-            for (key, agg) in map.iter() {
-                let cold = now.signed_duration_since(agg.last_seen) > self.window;
+            let mut map = self.map.lock().await;
+            for (key, agg) in map.iter_mut() {
+                let cold = now.signed_duration_since(agg.last_seen) > agg.open_for(self.window);
                 if cold {
                     to_evict.push(key.clone());
                     continue;
                 }
-                if !agg.dirty {
+                if !agg.dirty && agg.pending_variants.is_empty() && agg.unposted.is_empty() {
                     continue;
                 }
                 let throttled = agg
@@ -150,16 +706,63 @@ impl Aggregator {
                 if throttled {
                     continue;
                 }
-                let Some(posted) = &agg.posted else {
+                if agg.posted.is_empty() {
                     continue;
-                };
-                let view = build_view(agg);
-                to_update.push((key.clone(), view.to_blocks(), view.fallback_text(), posted.clone()));
+                }
+                if !agg.unposted.is_empty() {
+                    to_create.push((key.clone(), agg.unposted.clone()));
+                }
+                if !agg.pending_variants.is_empty() {
+                    let n = agg.pending_variants.len().min(self.thread_batch_size);
+                    let batch: Vec<Variant> = agg.pending_variants.drain(..n).collect();
+                    to_reply.push((key.clone(), batch, agg.posted.clone()));
+                }
+                if agg.dirty {
+                    let view = self.view(key, agg);
+                    to_update.push((key.clone(), view.to_blocks(), view.fallback_text(), agg.posted.clone()));
+                }
+            }
+        }
+
+        for (key, channels) in to_create {
+            let (blocks, fallback, stack_trace) = {
+                let map = self.map.lock().await;
+                let Some(agg) = map.get(&key) else { continue };
+                let view = self.view(&key, agg);
+                (view.to_blocks(), view.fallback_text(), view.sample.stack_trace().map(|t| t.to_string()))
+            };
+            let (posted, unposted) = self.create(&key, &channels, &blocks, &fallback, stack_trace.as_deref()).await;
+            let mut map = self.map.lock().await;
+            if let Some(agg) = map.get_mut(&key) {
+                agg.posted.extend(posted);
+                agg.unposted = unposted;
+                agg.last_edit = Some(Instant::now());
+                self.changed.store(true, Ordering::Relaxed);
+            }
+        }
+
+        for (key, variants, posted) in to_reply {
+            let view = ThreadView { variants: &variants };
+            let (blocks, fallback) = (view.to_blocks(), view.fallback_text());
+            for message in &posted {
+                if let Err(e) = self.notifier.reply(message, blocks.clone(), &fallback).await {
+                    log::warn!("slack thread reply failed for key {}: {}", key, e);
+                }
+            }
+            let mut map = self.map.lock().await;
+            if let Some(agg) = map.get_mut(&key) {
+                agg.last_edit = Some(Instant::now());
             }
         }
 
         for (key, blocks, fallback, posted) in to_update {
-            match self.slack.update(&posted, blocks, &fallback).await {
+            let mut result = Ok(());
+            for message in &posted {
+                if let Err(e) = self.notifier.update(message, blocks.clone(), &fallback).await {
+                    result = Err(e);
+                }
+            }
+            match result {
                 Ok(()) => {
                     let mut map = self.map.lock().await;
                     if let Some(agg) = map.get_mut(&key) {
@@ -178,33 +781,302 @@ impl Aggregator {
             }
         }
 
+        let mut evicted: Vec<(String, Aggregate)> = Vec::new();
         if !to_evict.is_empty() {
             let mut map = self.map.lock().await;
             for key in to_evict {
-                if let Some(agg) = map.get(&key) {
-                    if now.signed_duration_since(agg.last_seen) > self.window {
-                        log::info!(
-                            "evicting cold aggregate {} (count={}, container={})",
-                            key,
-                            agg.count,
-                            agg.container
-                        );
-                        map.remove(&key);
-                    }
+                if let Some(agg) = map.get(&key)
+                    && now.signed_duration_since(agg.last_seen) > agg.open_for(self.window)
+                {
+                    log::info!(
+                        "evicting cold aggregate {} (count={}, namespace={}, container={})",
+                        key,
+                        agg.count,
+                        agg.namespace,
+                        agg.container
+                    );
+                    let agg = map.remove(&key).expect("checked above");
+                    evicted.push((key, agg));
                 }
             }
         }
+
+        for (key, agg) in evicted {
+            self.close(&key, agg, None).await;
+        }
     }
 }
 
-fn build_view(agg: &Aggregate) -> AlertView<'_> {
-    AlertView {
-        sample: &agg.sample,
-        container: &agg.container,
-        count: agg.count,
-        first_seen: agg.first_seen,
-        last_seen: agg.last_seen,
-        pods: &agg.pods,
-        trace_ids: &agg.trace_ids,
+fn message_hash(message: &str) -> u64 {
+    let mut h = StableHasher::new();
+    h.write(message);
+    h.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifier::{Recorded, Recording};
+
+    fn aggregator(notifier: Arc<Recording>) -> Arc<Aggregator<Recording>> {
+        aggregator_with(notifier, Thresholds::default())
+    }
+
+    fn aggregator_with(notifier: Arc<Recording>, thresholds: Thresholds) -> Arc<Aggregator<Recording>> {
+        let router = Router::new(Vec::new(), vec!["C0ALERTS".into(), "C0OTHER".into()], "dev-gcp".into());
+        let config = Config {
+            cluster: "dev-gcp".into(),
+            window_seconds: 600,
+            edit_throttle_ms: 0,
+            thread_variants_max: 20,
+            thread_batch_size: 5,
+            fingerprint: Fingerprint { stack_frames: 5, in_app: vec!["no.nav".into()] },
+            thresholds,
+            slack_workspace_url: Some("https://nav-it.slack.com".into()),
+        };
+        Aggregator::new(notifier, router, config, None)
+    }
+
+    fn log(message: &str) -> Log {
+        serde_json::from_value(serde_json::json!({ "level": "ERROR", "message": message })).unwrap()
+    }
+
+    fn source(pod: &str) -> Source {
+        Source { namespace: "helved".into(), pod: pod.into(), container: "app".into(), previous: false }
+    }
+
+    #[tokio::test]
+    async fn first_occurrence_is_created_in_every_channel_then_updated() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+
+        aggregator.ingest(log("boom 1"), source("app-1")).await;
+        let created = notifier.sent();
+        assert_eq!(created.len(), 2);
+        assert!(matches!(&created[0], Recorded::Create { message, .. } if message.channel == "C0ALERTS"));
+        assert!(matches!(&created[1], Recorded::Create { message, .. } if message.channel == "C0OTHER"));
+
+        aggregator.ingest(log("boom 2"), source("app-2")).await;
+        aggregator.flush_tick().await;
+        let sent = notifier.sent();
+        assert!(sent.iter().any(|r| matches!(r, Recorded::Update { text, .. } if text.contains("(x2)"))));
+        assert!(sent.iter().any(|r| matches!(r, Recorded::Reply { .. })));
+    }
+
+    #[tokio::test]
+    async fn channel_that_failed_is_retried_on_flush() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+        notifier.failing.lock().unwrap().push("C0OTHER".into());
+
+        aggregator.ingest(log("boom"), source("app-1")).await;
+        assert_eq!(notifier.sent().len(), 1);
+        aggregator.flush_tick().await;
+        assert_eq!(notifier.sent().len(), 1);
+
+        notifier.failing.lock().unwrap().clear();
+        aggregator.flush_tick().await;
+        assert!(matches!(&notifier.sent()[1], Recorded::Create { message, .. } if message.channel == "C0OTHER"));
+        let map = aggregator.map.lock().await;
+        let agg = map.values().next().unwrap();
+        assert_eq!(agg.posted.len(), 2);
+        assert!(agg.unposted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn repeated_message_is_not_replied_twice() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+
+        aggregator.ingest(log("boom"), source("app-1")).await;
+        aggregator.ingest(log("boom"), source("app-1")).await;
+        aggregator.flush_tick().await;
+        assert!(!notifier.sent().iter().any(|r| matches!(r, Recorded::Reply { .. })));
+    }
+
+    #[tokio::test]
+    async fn cold_aggregate_is_resolved_in_every_channel() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+
+        aggregator.ingest(log("boom"), source("app-1")).await;
+        // Restored aggregates can have been quiet for much longer than the window.
+        for agg in aggregator.map.lock().await.values_mut() {
+            agg.last_seen = Utc::now() - ChronoDuration::hours(2);
+        }
+        aggregator.flush_tick().await;
+
+        let resolved: Vec<Recorded> =
+            notifier.sent().into_iter().filter(|r| matches!(r, Recorded::Resolve { .. })).collect();
+        assert_eq!(resolved.len(), 2);
+        assert!(matches!(&resolved[0], Recorded::Resolve { text, .. } if text.contains("resolved")));
+        assert!(matches!(&resolved[0], Recorded::Resolve { blocks, .. } if blocks.contains("quiet for 2h 0m")));
+        assert!(aggregator.map.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cold_aggregate_hit_before_the_flush_is_resolved_first() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+
+        aggregator.ingest(log("boom"), source("app-1")).await;
+        for agg in aggregator.map.lock().await.values_mut() {
+            agg.last_seen = Utc::now() - ChronoDuration::hours(2);
+        }
+        aggregator.ingest(log("boom"), source("app-1")).await;
+
+        let sent = notifier.sent();
+        assert_eq!(sent.iter().filter(|r| matches!(r, Recorded::Resolve { .. })).count(), 2);
+        assert_eq!(sent.iter().filter(|r| matches!(r, Recorded::Create { .. })).count(), 4);
+        assert_eq!(aggregator.recently_resolved().await.len(), 1);
+        assert_eq!(aggregator.map.lock().await.values().next().unwrap().count, 1);
+    }
+
+    #[tokio::test]
+    async fn threshold_rule_buffers_until_count_is_reached() {
+        let notifier = Arc::new(Recording::default());
+        let rules = serde_json::from_str(r#"[{ "message": "rebalanc", "count": 3, "minutes": 10 }]"#).unwrap();
+        let aggregator = aggregator_with(notifier.clone(), Thresholds::new(rules));
+
+        aggregator.ingest(log("Kafka rebalancing"), source("app-1")).await;
+        aggregator.ingest(log("Kafka rebalancing"), source("app-1")).await;
+        aggregator.flush_tick().await;
+        assert!(notifier.sent().is_empty());
+
+        aggregator.ingest(log("Kafka rebalancing"), source("app-2")).await;
+        let created: Vec<Recorded> =
+            notifier.sent().into_iter().filter(|r| matches!(r, Recorded::Create { .. })).collect();
+        assert_eq!(created.len(), 2);
+        assert!(matches!(&created[0], Recorded::Create { text, .. } if text.contains("(x3)")));
+        assert_eq!(aggregator.map.lock().await.values().next().unwrap().buffered, 3);
+
+        // Unmatched errors still post right away.
+        aggregator.ingest(log("boom"), source("app-1")).await;
+        assert_eq!(notifier.sent().iter().filter(|r| matches!(r, Recorded::Create { .. })).count(), 4);
+    }
+
+    #[tokio::test]
+    async fn silenced_events_are_counted_but_not_posted() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+        let new = serde_json::from_value(serde_json::json!({
+            "message": "timeout", "created_by": "kari", "comment": "known", "duration_minutes": 60
+        }))
+        .unwrap();
+        let silence = aggregator.add_silence(new).await.unwrap();
+
+        aggregator.ingest(log("read timeout"), source("app-1")).await;
+        aggregator.ingest(log("read timeout"), source("app-1")).await;
+        assert!(notifier.sent().is_empty());
+        let suppressed: u64 = aggregator.silences().await[0].suppressed.values().sum();
+        assert_eq!(suppressed, 2);
+        // A muted storm alone doesn't rewrite the state on every persist tick.
+        aggregator.changed.store(false, Ordering::Relaxed);
+        aggregator.ingest(log("read timeout"), source("app-1")).await;
+        assert!(!aggregator.changed.load(Ordering::Relaxed));
+
+        assert!(aggregator.expire_silence(&silence.id).await);
+        aggregator.ingest(log("read timeout"), source("app-1")).await;
+        assert_eq!(notifier.sent().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn slack_buttons_acknowledge_mute_and_resolve() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+        aggregator.ingest(log("boom"), source("app-1")).await;
+        let key = aggregator.map.lock().await.keys().next().unwrap().clone();
+        assert!(matches!(&notifier.sent()[0], Recorded::Create { blocks, .. } if blocks.contains(&key)));
+
+        assert!(!aggregator.acknowledge("nope", "kari").await);
+        assert!(aggregator.acknowledge(&key, "kari").await);
+        assert!(aggregator.mute(&key, "ola", 60).await.unwrap());
+        aggregator.flush_tick().await;
+        let updated = notifier.sent().into_iter().find_map(|r| match r {
+            Recorded::Update { blocks, .. } => Some(blocks),
+            _ => None,
+        });
+        let updated = updated.unwrap();
+        assert!(updated.contains("acknowledged by kari"));
+        assert!(updated.contains("muted by ola until"));
+        assert_eq!(aggregator.silences().await[0].matchers.key.as_deref(), Some(key.as_str()));
+
+        assert!(aggregator.resolve(&key, "kari").await);
+        assert!(aggregator.map.lock().await.is_empty());
+        assert!(notifier.sent().iter().any(|r| matches!(r, Recorded::Resolve { blocks, .. } if blocks.contains("resolved by kari"))));
+        assert_eq!(aggregator.recently_resolved().await[0].resolved_by.as_deref(), Some("kari"));
+        assert!(!aggregator.resolve(&key, "kari").await);
+    }
+
+    #[tokio::test]
+    async fn summaries_show_open_aggregates_newest_first() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+        aggregator.ingest(log("boom"), source("app-1")).await;
+        aggregator.ingest(log("bang"), source("app-2")).await;
+        aggregator.ingest(log("bang"), source("app-1")).await;
+
+        let summaries = aggregator.summaries().await;
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].message, "bang");
+        assert_eq!(summaries[0].pods, vec!["app-1", "app-2"]);
+        assert!(summaries[0].dirty);
+        assert_eq!(summaries[1].permalinks, vec!["https://nav-it.slack.com/archives/C0ALERTS/p0", "https://nav-it.slack.com/archives/C0OTHER/p1"]);
+
+        let detail = aggregator.detail(&summaries[1].key).await.unwrap();
+        assert_eq!(detail.sample.message(), "boom");
+        assert!(aggregator.detail("nope").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn spike_lists_each_pod_that_logged() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+        let spike = crate::model::Spike {
+            level: "WARN".into(),
+            bucket_seconds: 60,
+            count: 240,
+            baseline: 10.0,
+            history: vec![10, 240],
+            pods: vec!["app-1".into(), "app-2".into()],
+        };
+        aggregator.ingest(Log::from_spike(spike), source("app-1")).await;
+        assert_eq!(aggregator.summaries().await[0].pods, vec!["app-1", "app-2"]);
+    }
+
+    #[tokio::test]
+    async fn object_exception_is_redacted_before_it_reaches_the_api() {
+        let notifier = Arc::new(Recording::default());
+        let aggregator = aggregator(notifier.clone());
+        let mut log: Log = serde_json::from_value(serde_json::json!({
+            "level": "ERROR",
+            "message": "feilet",
+            "exception": {
+                "class": "no.nav.PersonException",
+                "message": "Fant ikke 01018012371",
+                "cause": [{ "message": "kari@nav.no" }]
+            }
+        }))
+        .unwrap();
+        log.redact(&crate::redact::Redactor::new(&[], Vec::new()).unwrap());
+        aggregator.ingest(log, source("app-1")).await;
+
+        let key = aggregator.summaries().await[0].key.clone();
+        let json = serde_json::to_string(&aggregator.detail(&key).await.unwrap()).unwrap();
+        assert!(json.contains("Fant ikke <fnr>"));
+        assert!(json.contains("no.nav.PersonException"));
+        for needle in ["01018012371", "kari@nav.no"] {
+            assert!(!json.contains(needle), "{needle} leaked into {json}");
+        }
+    }
+
+    #[test]
+    fn threshold_window_slides() {
+        let rule: Rule = serde_json::from_str(r#"{ "count": 2, "minutes": 1 }"#).unwrap();
+        let mut threshold = Threshold::new(&rule);
+        let t0 = Utc::now();
+        assert!(!threshold.record(t0));
+        assert!(!threshold.record(t0 + ChronoDuration::seconds(61)));
+        assert!(threshold.record(t0 + ChronoDuration::seconds(90)));
     }
 }
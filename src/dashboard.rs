@@ -0,0 +1,235 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use chrono::{DateTime, Utc};
+
+use crate::aggregator::AggregateSummary;
+use crate::history::{ContainerActivity, Resolved, MINUTES};
+use crate::model::{format_duration, sparkline, truncate};
+
+/// Seconds between automatic reloads of the page.
+const REFRESH_SECONDS: u32 = 15;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:1.5em;color:#1d1c1d}\
+table{border-collapse:collapse;width:100%;margin-bottom:2em}\
+th,td{text-align:left;padding:.3em .6em;border-bottom:1px solid #ddd;vertical-align:top}\
+th{background:#f4f4f4}td.num{text-align:right}\
+.spark{font-family:monospace;letter-spacing:-1px}.msg{font-family:monospace;font-size:.9em}\
+.muted{color:#777}form{margin-bottom:1.5em}";
+
+/// What the dashboard shows, from its query string. Unset matchers match everything.
+#[derive(Default)]
+pub struct Filter {
+    pub namespace: Option<String>,
+    pub container: Option<String>,
+    pub logger: Option<String>,
+    /// Highest count first instead of most recently seen first.
+    pub by_count: bool,
+}
+
+impl Filter {
+    fn matches(&self, namespace: &str, container: &str, logger: Option<&str>) -> bool {
+        self.matches_container(namespace, container) && self.logger.as_deref().is_none_or(|l| Some(l) == logger)
+    }
+
+    /// Per-container activity has no logger, so only the other matchers apply.
+    fn matches_container(&self, namespace: &str, container: &str) -> bool {
+        self.namespace.as_deref().is_none_or(|n| n == namespace)
+            && self.container.as_deref().is_none_or(|c| c == container)
+    }
+}
+
+/// The whole page. Open aggregates come most recently seen first, as from
+/// `Aggregator::summaries`.
+pub fn render(
+    filter: &Filter,
+    mut aggregates: Vec<AggregateSummary>,
+    activity: Vec<ContainerActivity>,
+    resolved: Vec<Resolved>,
+    now: DateTime<Utc>,
+) -> String {
+    let namespaces: BTreeSet<&str> = aggregates.iter().map(|a| a.namespace.as_str())
+        .chain(resolved.iter().map(|r| r.namespace.as_str()))
+        .chain(activity.iter().map(|a| a.namespace.as_str()))
+        .collect();
+    let containers: BTreeSet<&str> = aggregates.iter().map(|a| a.container.as_str())
+        .chain(resolved.iter().map(|r| r.container.as_str()))
+        .chain(activity.iter().map(|a| a.container.as_str()))
+        .collect();
+    let loggers: BTreeSet<&str> = aggregates.iter().filter_map(|a| a.logger.as_deref())
+        .chain(resolved.iter().filter_map(|r| r.logger.as_deref()))
+        .collect();
+
+    let mut page = String::new();
+    let _ = write!(
+        page,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"{REFRESH_SECONDS}\">\
+         <title>helved-logs</title><style>{STYLE}</style></head><body><h1>helved-logs</h1>\
+         <form method=\"get\" action=\"/dashboard\">{}{}{}\
+         <select name=\"sort\"><option value=\"recent\">most recent</option><option value=\"count\"{}>highest count</option></select> \
+         <button>filter</button> <a href=\"/dashboard\">clear</a></form>",
+        select("namespace", &namespaces, filter.namespace.as_deref()),
+        select("container", &containers, filter.container.as_deref()),
+        select("logger", &loggers, filter.logger.as_deref()),
+        if filter.by_count { " selected" } else { "" },
+    );
+
+    aggregates.retain(|a| filter.matches(&a.namespace, &a.container, a.logger.as_deref()));
+    if filter.by_count {
+        // Stable, so equal counts stay most recent first.
+        aggregates.sort_by_key(|a| std::cmp::Reverse(a.count));
+    }
+    let _ = write!(
+        page,
+        "<h2>Open ({})</h2><table><tr><th>count</th><th>container</th><th>logger</th><th>message</th>\
+         <th>first seen</th><th>last seen</th><th>pods</th><th>status</th><th>slack</th></tr>",
+        aggregates.len()
+    );
+    for a in &aggregates {
+        let mut status = Vec::new();
+        if a.buffering {
+            status.push("held back by threshold".to_string());
+        }
+        if let Some(user) = &a.acked_by {
+            status.push(format!("acked by {}", escape(user)));
+        }
+        if a.dirty || a.pending_variants > 0 {
+            status.push("edit pending".to_string());
+        }
+        let _ = write!(
+            page,
+            "<tr><td class=\"num\">{}</td><td>{}/{}</td><td>{}</td><td class=\"msg\">{}</td>\
+             <td>{} ago</td><td>{} ago</td><td class=\"num\">{}</td><td>{}</td><td>{}</td></tr>",
+            a.count,
+            escape(&a.namespace),
+            escape(&a.container),
+            escape(a.logger.as_deref().unwrap_or("-")),
+            escape(&truncate(&a.message, 200)),
+            format_duration(now - a.first_seen),
+            format_duration(now - a.last_seen),
+            a.pods.len(),
+            status.join(", "),
+            links(&a.permalinks),
+        );
+    }
+    page.push_str("</table>");
+
+    let _ = write!(
+        page,
+        "<h2>Errors per minute, last {MINUTES} minutes</h2><table><tr><th>container</th><th>errors</th><th>total</th></tr>"
+    );
+    for a in activity.iter().filter(|a| filter.matches_container(&a.namespace, &a.container)) {
+        let _ = write!(
+            page,
+            "<tr><td>{}/{}</td><td class=\"spark\">{}</td><td class=\"num\">{}</td></tr>",
+            escape(&a.namespace),
+            escape(&a.container),
+            sparkline(&a.per_minute),
+            a.per_minute.iter().sum::<u64>(),
+        );
+    }
+    page.push_str("</table>");
+
+    let resolved: Vec<&Resolved> = resolved
+        .iter()
+        .filter(|r| filter.matches(&r.namespace, &r.container, r.logger.as_deref()))
+        .collect();
+    let _ = write!(
+        page,
+        "<h2>Recently resolved ({})</h2><table><tr><th>count</th><th>container</th><th>logger</th><th>message</th>\
+         <th>lasted</th><th>resolved</th><th>slack</th></tr>",
+        resolved.len()
+    );
+    for r in resolved {
+        let by = match &r.resolved_by {
+            Some(user) => format!(" by {}", escape(user)),
+            None => String::new(),
+        };
+        let _ = write!(
+            page,
+            "<tr><td class=\"num\">{}</td><td>{}/{}</td><td>{}</td><td class=\"msg\">{}</td>\
+             <td>{}</td><td>{} ago{}</td><td>{}</td></tr>",
+            r.count,
+            escape(&r.namespace),
+            escape(&r.container),
+            escape(r.logger.as_deref().unwrap_or("-")),
+            escape(&truncate(&r.message, 200)),
+            format_duration(r.last_seen - r.first_seen),
+            format_duration(now - r.resolved_at),
+            by,
+            links(&r.permalinks),
+        );
+    }
+    page.push_str("</table><p class=\"muted\">JSON at <a href=\"/api/aggregates\">/api/aggregates</a></p></body></html>");
+    page
+}
+
+fn select(name: &str, values: &BTreeSet<&str>, selected: Option<&str>) -> String {
+    let mut html = format!("<select name=\"{name}\"><option value=\"\">all {name}s</option>");
+    for value in values {
+        let marked = if Some(*value) == selected { " selected" } else { "" };
+        let value = escape(value);
+        let _ = write!(html, "<option value=\"{value}\"{marked}>{value}</option>");
+    }
+    html.push_str("</select> ");
+    html
+}
+
+fn links(permalinks: &[String]) -> String {
+    permalinks
+        .iter()
+        .enumerate()
+        .map(|(i, url)| format!("<a href=\"{}\">#{}</a>", escape(url), i + 1))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(container: &str, message: &str, count: u32) -> AggregateSummary {
+        AggregateSummary {
+            key: format!("helved/{container}||1"),
+            namespace: "helved".into(),
+            container: container.into(),
+            logger: Some("no.nav.Foo".into()),
+            level: "ERROR".into(),
+            message: message.into(),
+            count,
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            pods: vec!["app-1".into()],
+            trace_ids: Vec::new(),
+            permalinks: vec!["https://nav-it.slack.com/archives/C1/p1".into()],
+            dirty: false,
+            pending_variants: 0,
+            throttled_ms: 0,
+            buffering: false,
+            acked_by: None,
+        }
+    }
+
+    #[test]
+    fn filters_sorts_and_escapes() {
+        let aggregates = vec![summary("app", "<script>", 1), summary("app", "busy", 9), summary("worker", "quiet", 5)];
+        let activity = vec![ContainerActivity { namespace: "helved".into(), container: "worker".into(), per_minute: vec![0, 2] }];
+        let filter = Filter { container: Some("app".into()), logger: Some("no.nav.Foo".into()), by_count: true, ..Filter::default() };
+
+        let page = render(&filter, aggregates, activity, Vec::new(), Utc::now());
+        assert!(page.contains("<h2>Open (2)</h2>"));
+        assert!(page.find("busy").unwrap() < page.find("&lt;script&gt;").unwrap());
+        assert!(!page.contains("<script>"));
+        assert!(!page.contains("quiet"));
+        assert!(page.contains("<option value=\"app\" selected>app</option>"));
+        assert!(!page.contains("▁█"));
+    }
+}
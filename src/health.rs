@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::notifier::Notifier;
+
+tokio::task_local! {
+    /// The task an item passed to `Task::run` belongs to, so `waiting` can find it.
+    static CURRENT: (Arc<Health>, &'static str);
+}
+
+/// What `/ready` and `/live` report. Readiness needs the pod watcher's initial list and a
+/// working notifier; liveness fails once a long running task has exited, or has been busy
+/// with a single item for longer than `deadline`, not counting time spent `waiting`.
+#[derive(Debug)]
+pub struct Health {
+    deadline: Duration,
+    synced: AtomicBool,
+    notifier_ok: AtomicBool,
+    tasks: Mutex<BTreeMap<&'static str, TaskState>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TaskState {
+    busy_since: Option<Instant>,
+    /// Blocked on something outside the process, e.g. Slack's rate limits.
+    waiting: bool,
+    exited: bool,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            deadline: Duration::from_secs(300),
+            synced: AtomicBool::new(false),
+            notifier_ok: AtomicBool::new(false),
+            tasks: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Health {
+    pub fn new(deadline: Duration) -> Arc<Self> {
+        Arc::new(Health { deadline, ..Health::default() })
+    }
+
+    /// The pod watcher has listed every pod once.
+    pub fn synced(&self) {
+        self.synced.store(true, Ordering::Relaxed);
+    }
+
+    /// Register a long running task. It counts as exited once the handle is dropped,
+    /// whether it returned, failed or panicked.
+    pub fn task(self: &Arc<Self>, name: &'static str) -> Task {
+        self.set(name, TaskState { busy_since: None, waiting: false, exited: false });
+        Task { health: self.clone(), name }
+    }
+
+    fn set(&self, name: &'static str, state: TaskState) {
+        self.tasks.lock().unwrap().insert(name, state);
+    }
+
+    /// Mark `name` as waiting until the guard is dropped. Its busy clock starts over
+    /// afterwards, so only the time spent on its own work counts towards the deadline.
+    fn wait(&self, name: &'static str) -> Waiting<'_> {
+        if let Some(state) = self.tasks.lock().unwrap().get_mut(name) {
+            state.waiting = true;
+        }
+        Waiting { health: self, name }
+    }
+
+    pub fn ready(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        if !self.synced.load(Ordering::Relaxed) {
+            problems.push("pod watcher has not synced".to_string());
+        }
+        if !self.notifier_ok.load(Ordering::Relaxed) {
+            problems.push("notifier check has not passed".to_string());
+        }
+        if problems.is_empty() { Ok(()) } else { Err(problems) }
+    }
+
+    pub fn live(&self) -> Result<(), Vec<String>> {
+        self.live_at(Instant::now())
+    }
+
+    fn live_at(&self, now: Instant) -> Result<(), Vec<String>> {
+        let problems: Vec<String> = self
+            .tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(name, state)| match state.busy_since {
+                _ if state.exited => Some(format!("{name} has exited")),
+                _ if state.waiting => None,
+                Some(since) if now.saturating_duration_since(since) > self.deadline => {
+                    Some(format!("{name} has been stuck for {:?}", now.saturating_duration_since(since)))
+                }
+                _ => None,
+            })
+            .collect();
+        if problems.is_empty() { Ok(()) } else { Err(problems) }
+    }
+
+    /// Run the notifier check now and then every `every`, so a revoked token takes the
+    /// pod out of readiness.
+    pub fn spawn_notifier_check<N: Notifier>(
+        self: Arc<Self>,
+        notifier: Arc<N>,
+        every: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(every);
+            loop {
+                ticker.tick().await;
+                self.check_notifier(notifier.as_ref()).await;
+            }
+        })
+    }
+
+    /// Run one notifier check and record whether it passed.
+    pub async fn check_notifier<N: Notifier>(&self, notifier: &N) {
+        let ok = match notifier.check().await {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("notifier check failed: {}", e);
+                false
+            }
+        };
+        self.notifier_ok.store(ok, Ordering::Relaxed);
+    }
+}
+
+/// Handle held by a long running task for as long as it runs.
+pub struct Task {
+    health: Arc<Health>,
+    name: &'static str,
+}
+
+impl Task {
+    /// Mark the task as busy with one item until the guard is dropped. Waiting for the
+    /// next item is not busy, so a quiet task never counts as stuck.
+    pub fn busy(&self) -> Busy<'_> {
+        self.health.set(self.name, TaskState { busy_since: Some(Instant::now()), waiting: false, exited: false });
+        Busy { task: self }
+    }
+
+    /// Run one item as busy, with the time it spends in `waiting` left out.
+    pub async fn run<F: Future>(&self, item: F) -> F::Output {
+        let _busy = self.busy();
+        CURRENT.scope((self.health.clone(), self.name), item).await
+    }
+}
+
+/// Wait for `f` without the task running the current item counting as stuck meanwhile.
+/// For waits on other systems, like Slack throttling us; outside `Task::run` it just
+/// awaits `f`.
+pub async fn waiting<F: Future>(f: F) -> F::Output {
+    let Ok((health, name)) = CURRENT.try_with(|current| current.clone()) else {
+        return f.await;
+    };
+    let _waiting = health.wait(name);
+    f.await
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        log::error!("task {} has exited", self.name);
+        self.health.set(self.name, TaskState { busy_since: None, waiting: false, exited: true });
+    }
+}
+
+pub struct Busy<'a> {
+    task: &'a Task,
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.task.health.set(self.task.name, TaskState { busy_since: None, waiting: false, exited: false });
+    }
+}
+
+impl Busy<'_> {
+    /// Like `waiting`, for a task that holds its guard itself instead of using `Task::run`.
+    pub async fn waiting<F: Future>(&self, f: F) -> F::Output {
+        let _waiting = self.task.health.wait(self.task.name);
+        f.await
+    }
+}
+
+struct Waiting<'a> {
+    health: &'a Health,
+    name: &'static str,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = self.health.tasks.lock().unwrap().get_mut(self.name) {
+            state.waiting = false;
+            state.busy_since = state.busy_since.map(|_| Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn live_fails_when_a_task_exits_or_is_stuck() {
+        let health = Health::new(Duration::from_secs(60));
+        let watcher = health.task("watcher");
+        let flush = health.task("flush");
+        assert!(health.live().is_ok());
+
+        let busy = flush.busy();
+        assert!(health.live().is_ok());
+        assert!(health.live_at(Instant::now() + Duration::from_secs(61)).is_err());
+        drop(busy);
+        assert!(health.live_at(Instant::now() + Duration::from_secs(61)).is_ok());
+
+        drop(watcher);
+        assert_eq!(health.live().unwrap_err(), vec!["watcher has exited".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn time_spent_waiting_is_not_stuck() {
+        let health = Health::new(Duration::from_secs(60));
+        let consumer = health.task("consumer");
+        let later = Instant::now() + Duration::from_secs(61);
+
+        consumer
+            .run(async {
+                waiting(async {
+                    assert!(health.live_at(later).is_ok());
+                })
+                .await;
+                assert!(health.live_at(later).is_err());
+            })
+            .await;
+        assert!(health.live_at(later).is_ok());
+        // Outside a task's item it is a plain await.
+        waiting(async {}).await;
+    }
+
+    #[tokio::test]
+    async fn ready_needs_sync_and_a_working_notifier() {
+        let health = Health::new(Duration::from_secs(60));
+        assert_eq!(health.ready().unwrap_err().len(), 2);
+
+        health.synced();
+        assert_eq!(health.ready().unwrap_err().len(), 1);
+        health.check_notifier(&crate::notifier::Stdout::default()).await;
+        assert!(health.ready().is_ok());
+    }
+}
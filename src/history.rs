@@ -0,0 +1,108 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Minutes of per-container activity kept for the dashboard.
+pub const MINUTES: usize = 60;
+/// Resolved aggregates kept for the dashboard.
+const RESOLVED_KEPT: usize = 50;
+
+/// An aggregate that has closed, as listed on the dashboard.
+#[derive(Serialize, Debug, Clone)]
+pub struct Resolved {
+    pub key: String,
+    pub namespace: String,
+    pub container: String,
+    pub logger: Option<String>,
+    pub message: String,
+    pub count: u32,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub resolved_at: DateTime<Utc>,
+    /// Set when someone resolved it from Slack rather than it going cold.
+    pub resolved_by: Option<String>,
+    pub permalinks: Vec<String>,
+}
+
+/// Errors per minute for one container over the last `MINUTES`, oldest first.
+#[derive(Serialize, Debug, Clone)]
+pub struct ContainerActivity {
+    pub namespace: String,
+    pub container: String,
+    pub per_minute: Vec<u64>,
+}
+
+/// Recent activity that outlives the aggregates themselves. In memory only, it starts
+/// over on restart.
+#[derive(Default)]
+pub struct History {
+    /// Minute (unix time / 60) and count, per namespace and container.
+    containers: BTreeMap<(String, String), VecDeque<(i64, u64)>>,
+    resolved: VecDeque<Resolved>,
+}
+
+impl History {
+    pub fn record(&mut self, namespace: &str, container: &str, at: DateTime<Utc>) {
+        let minute = at.timestamp().div_euclid(60);
+        let buckets = self.containers.entry((namespace.to_string(), container.to_string())).or_default();
+        match buckets.back_mut() {
+            Some((last, count)) if *last == minute => *count += 1,
+            _ => buckets.push_back((minute, 1)),
+        }
+        while buckets.front().is_some_and(|(m, _)| *m <= minute - MINUTES as i64) {
+            buckets.pop_front();
+        }
+    }
+
+    pub fn resolved(&mut self, resolved: Resolved) {
+        self.resolved.push_front(resolved);
+        self.resolved.truncate(RESOLVED_KEPT);
+    }
+
+    /// Newest first.
+    pub fn recently_resolved(&self) -> Vec<Resolved> {
+        self.resolved.iter().cloned().collect()
+    }
+
+    /// Containers with errors in the last `MINUTES`. Quiet ones are dropped.
+    pub fn activity(&mut self, now: DateTime<Utc>) -> Vec<ContainerActivity> {
+        let current = now.timestamp().div_euclid(60);
+        let first = current - MINUTES as i64 + 1;
+        self.containers.retain(|_, buckets| buckets.back().is_some_and(|(m, _)| *m >= first));
+        self.containers
+            .iter()
+            .map(|((namespace, container), buckets)| {
+                let mut per_minute = vec![0; MINUTES];
+                for (minute, count) in buckets {
+                    if (first..=current).contains(minute) {
+                        per_minute[(minute - first) as usize] = *count;
+                    }
+                }
+                ContainerActivity { namespace: namespace.clone(), container: container.clone(), per_minute }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn counts_per_minute_and_forgets_quiet_containers() {
+        let now = DateTime::parse_from_rfc3339("2025-05-19T08:30:30Z").unwrap().with_timezone(&Utc);
+        let mut history = History::default();
+        history.record("helved", "app", now - Duration::minutes(2));
+        history.record("helved", "app", now);
+        history.record("helved", "app", now);
+        history.record("helved", "old", now - Duration::minutes(61));
+
+        let activity = history.activity(now);
+        assert_eq!(activity.len(), 1);
+        assert_eq!(activity[0].per_minute.len(), MINUTES);
+        assert_eq!(&activity[0].per_minute[MINUTES - 3..], &[1, 0, 2]);
+        assert!(history.activity(now + Duration::minutes(60)).is_empty());
+    }
+}
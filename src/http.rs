@@ -0,0 +1,377 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// Largest request head we read, request line and headers together.
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+/// Largest request body we accept.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/// A client gets this long to send its whole request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long shutdown waits for requests in flight.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+pub struct Request {
+    pub method: String,
+    /// Without the query string.
+    pub path: String,
+    /// Decoded query parameters, in order.
+    pub query: Vec<(String, String)>,
+    /// Header names are lower-cased.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Decoded `{name}` segments of the matched route.
+    pub params: Vec<(&'static str, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// A query parameter, `None` if missing or empty.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(n, v)| n == name && !v.is_empty()).map(|(_, v)| v.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> &str {
+        self.params.iter().find(|(n, _)| *n == name).map_or("", |(_, v)| v.as_str())
+    }
+
+    /// The body as JSON, or a 400 response saying what is wrong with it.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Response> {
+        serde_json::from_slice(&self.body).map_err(|e| Response::text(400, &format!("invalid body: {e}")))
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn text(status: u16, body: &str) -> Self {
+        Response { status, content_type: "text/plain; charset=utf-8", body: body.as_bytes().to_vec() }
+    }
+
+    pub fn html(status: u16, body: String) -> Self {
+        Response { status, content_type: "text/html; charset=utf-8", body: body.into_bytes() }
+    }
+
+    pub fn json<T: Serialize + ?Sized>(status: u16, body: &T) -> Self {
+        match serde_json::to_vec(body) {
+            Ok(body) => Response { status, content_type: "application/json", body },
+            Err(e) => Response::text(500, &e.to_string()),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+type Handler<S> = Box<dyn Fn(Request, Arc<S>) -> BoxFuture<'static, Response> + Send + Sync>;
+
+struct Route<S> {
+    method: &'static str,
+    /// Path segments; `{name}` matches any one segment.
+    segments: Vec<&'static str>,
+    handler: Handler<S>,
+}
+
+/// Maps method and path to a handler. Handlers get the request and the shared state `S`.
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+    /// Paths polled often enough that their access log goes to debug.
+    quiet: Vec<&'static str>,
+}
+
+impl<S: Send + Sync + 'static> Router<S> {
+    pub fn new() -> Self {
+        Router { routes: Vec::new(), quiet: Vec::new() }
+    }
+
+    pub fn route<F, Fut>(mut self, method: &'static str, pattern: &'static str, handler: F) -> Self
+    where
+        F: Fn(Request, Arc<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            segments: pattern.split('/').collect(),
+            handler: Box::new(move |request, state| Box::pin(handler(request, state))),
+        });
+        self
+    }
+
+    pub fn quiet(mut self, paths: &[&'static str]) -> Self {
+        self.quiet.extend_from_slice(paths);
+        self
+    }
+
+    async fn dispatch(&self, mut request: Request, state: Arc<S>) -> Response {
+        let segments: Vec<&str> = request.path.split('/').collect();
+        let mut path_matched = false;
+        for route in &self.routes {
+            let Some(params) = route.matches(&segments) else { continue };
+            if route.method != request.method {
+                path_matched = true;
+                continue;
+            }
+            request.params = params;
+            return (route.handler)(request, state).await;
+        }
+        if path_matched {
+            Response::text(405, "method not allowed")
+        } else {
+            Response::text(404, "not found")
+        }
+    }
+}
+
+impl<S> Route<S> {
+    fn matches(&self, segments: &[&str]) -> Option<Vec<(&'static str, String)>> {
+        if segments.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = Vec::new();
+        for (pattern, segment) in self.segments.iter().zip(segments) {
+            match pattern.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(name) if !segment.is_empty() => params.push((name, decode(segment))),
+                Some(_) => return None,
+                None if pattern == segment => {}
+                None => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+/// Serve `router` on `addr` until `shutdown` resolves, then stop accepting and give
+/// requests in flight a little while to finish.
+pub async fn serve<S: Send + Sync + 'static>(
+    addr: &str,
+    router: Router<S>,
+    state: Arc<S>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .context(format!("Failed to bind TCP listener to {}", addr))?;
+    log::info!("[HTTP] listening on {}", addr);
+
+    let router = Arc::new(router);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _addr)) => {
+                    connections.spawn(connection(socket, router.clone(), state.clone()));
+                }
+                Err(e) => {
+                    log::error!("[HTTP ERROR] Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            },
+        }
+    }
+
+    drop(listener);
+    log::info!("[HTTP] shutting down, {} requests in flight", connections.len());
+    let drained = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        log::warn!("[HTTP] aborting {} requests still in flight", connections.len());
+    }
+    Ok(())
+}
+
+async fn connection<S: Send + Sync + 'static>(
+    mut socket: tokio::net::TcpStream,
+    router: Arc<Router<S>>,
+    state: Arc<S>,
+) {
+    let started = Instant::now();
+    let (response, method, path) = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut socket)).await {
+        Ok(Ok(request)) => {
+            let (method, path) = (request.method.clone(), request.path.clone());
+            (router.dispatch(request, state).await, method, path)
+        }
+        Ok(Err(rejected)) => (rejected, String::from("-"), String::from("-")),
+        Err(_) => (Response::text(408, "request timeout"), String::from("-"), String::from("-")),
+    };
+    let level = if response.status < 400 && router.quiet.contains(&path.as_str()) {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+    log::log!(
+        level,
+        "[HTTP] {} {} {} {}B {}ms",
+        method,
+        path,
+        response.status,
+        response.body.len(),
+        started.elapsed().as_millis()
+    );
+    if let Err(e) = socket.write_all(&response.to_bytes()).await
+        && e.kind() != std::io::ErrorKind::BrokenPipe
+    {
+        log::error!("[HTTP ERROR] Failed to write response: {}", e);
+    }
+}
+
+/// Read one request, or the response explaining why it was rejected.
+async fn read_request<R: AsyncRead + Unpin>(socket: &mut R) -> Result<Request, Response> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let head_end = loop {
+        let end = buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4);
+        if end.unwrap_or(buf.len()) > MAX_HEAD_BYTES {
+            return Err(Response::text(431, "request headers too large"));
+        }
+        if let Some(end) = end {
+            break end;
+        }
+        let n = socket.read(&mut chunk).await.map_err(|e| Response::text(400, &e.to_string()))?;
+        if n == 0 {
+            return Err(Response::text(400, "connection closed before end of headers"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    if content_length > MAX_BODY_BYTES {
+        return Err(Response::text(413, "request body too large"));
+    }
+
+    let mut body = buf.split_off(head_end);
+    while body.len() < content_length {
+        let n = socket.read(&mut chunk).await.map_err(|e| Response::text(400, &e.to_string()))?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(content_length);
+
+    Ok(Request {
+        method,
+        path: path.to_string(),
+        query: query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode(name), decode(value))
+            })
+            .collect(),
+        headers,
+        body,
+        params: Vec::new(),
+    })
+}
+
+fn decode(s: &str) -> String {
+    let s = s.replace('+', " ");
+    urlencoding::decode(&s).map(|d| d.into_owned()).unwrap_or(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(raw: &[u8]) -> Result<Request, Response> {
+        let (mut client, mut server) = tokio::io::duplex(256 * 1024);
+        client.write_all(raw).await.unwrap();
+        drop(client);
+        read_request(&mut server).await
+    }
+
+    async fn echo(request: Request, _: Arc<()>) -> Response {
+        Response::text(200, request.param("id"))
+    }
+
+    #[tokio::test]
+    async fn parses_requests_and_enforces_limits() {
+        let request = read(b"POST /silences?q=a+b%2Fc&x= HTTP/1.1\r\nContent-Length: 4\r\nX-Slack-Signature: v0=1\r\n\r\n{}{}trailing")
+            .await
+            .unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/silences"));
+        assert_eq!((request.query("q"), request.query("x")), (Some("a b/c"), None));
+        assert_eq!(request.header("x-slack-signature"), Some("v0=1"));
+        assert_eq!(request.body, b"{}{}");
+
+        let too_large = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_BYTES + 1);
+        assert_eq!(read(too_large.as_bytes()).await.err().unwrap().status, 413);
+        let huge_head = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD_BYTES));
+        assert_eq!(read(huge_head.as_bytes()).await.err().unwrap().status, 431);
+    }
+
+    #[tokio::test]
+    async fn routes_by_method_and_path_with_params() {
+        let router = Router::new().route("DELETE", "/silences/{id}", echo).route("GET", "/", echo);
+        let dispatch = |raw: &'static [u8]| {
+            let router = &router;
+            async move { router.dispatch(read(raw).await.unwrap(), Arc::new(())).await }
+        };
+
+        let response = dispatch(b"DELETE /silences/a%2Fb?q=1 HTTP/1.1\r\n\r\n").await;
+        assert_eq!((response.status, response.body.as_slice()), (200, b"a/b".as_slice()));
+        assert_eq!(dispatch(b"GET /silences/x HTTP/1.1\r\n\r\n").await.status, 405);
+        assert_eq!(dispatch(b"GET /silences/ HTTP/1.1\r\n\r\n").await.status, 404);
+        assert_eq!(dispatch(b"GET /nope HTTP/1.1\r\n\r\n").await.status, 404);
+        assert_eq!(dispatch(b"GET / HTTP/1.1\r\n\r\n").await.status, 200);
+    }
+}
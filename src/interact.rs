@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use aws_lc_rs::hmac;
+use serde::Deserialize;
+
+use crate::aggregator::Aggregator;
+use crate::notifier::Notifier;
+
+/// Requests older than this are rejected, so a captured request can't be replayed.
+const MAX_AGE_SECONDS: i64 = 5 * 60;
+
+/// Checks that interaction requests were sent by Slack, with the app's signing secret.
+pub struct Verifier {
+    key: hmac::Key,
+}
+
+impl Verifier {
+    pub fn new(secret: &str) -> Self {
+        Verifier { key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()) }
+    }
+
+    /// From `SLACK_SIGNING_SECRET`. Without it the interaction endpoint is disabled.
+    pub fn from_env() -> Option<Self> {
+        crate::env_opt("SLACK_SIGNING_SECRET").map(|secret| Verifier::new(&secret))
+    }
+
+    /// Verify the `X-Slack-Signature` of a request body, given its
+    /// `X-Slack-Request-Timestamp` and the current unix time.
+    pub fn verify(&self, timestamp: &str, signature: &str, body: &[u8], now: i64) -> Result<()> {
+        let sent: i64 = timestamp.parse().context("invalid request timestamp")?;
+        if (now - sent).abs() > MAX_AGE_SECONDS {
+            bail!("request timestamp is too old");
+        }
+        let tag = signature
+            .strip_prefix("v0=")
+            .and_then(decode_hex)
+            .context("invalid request signature")?;
+        hmac::verify(&self.key, &signed_content(timestamp, body), &tag)
+            .map_err(|_| anyhow::anyhow!("request signature does not match"))
+    }
+
+    /// The `X-Slack-Signature` Slack would send for this body.
+    #[cfg(test)]
+    pub fn sign(&self, timestamp: &str, body: &[u8]) -> String {
+        let tag = hmac::sign(&self.key, &signed_content(timestamp, body));
+        let hex: String = tag.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+        format!("v0={hex}")
+    }
+}
+
+fn signed_content(timestamp: &str, body: &[u8]) -> Vec<u8> {
+    let mut content = format!("v0:{timestamp}:").into_bytes();
+    content.extend_from_slice(body);
+    content
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The parts of a `block_actions` payload we use.
+#[derive(Deserialize, Debug)]
+pub struct Payload {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub user: User,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct User {
+    pub id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl User {
+    fn label(&self) -> &str {
+        self.username.as_deref().or(self.name.as_deref()).unwrap_or(&self.id)
+    }
+}
+
+/// A button click. Link buttons have no value and are ignored.
+#[derive(Deserialize, Debug)]
+pub struct Action {
+    pub action_id: String,
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+/// Slack posts interactions form encoded, with the JSON in a `payload` field.
+pub fn parse(body: &[u8]) -> Result<Payload> {
+    let body = std::str::from_utf8(body).context("body is not utf-8")?;
+    let encoded = body
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("payload="))
+        .context("missing payload field")?;
+    let json = urlencoding::decode(&encoded.replace('+', " "))
+        .context("payload is not url encoded")?
+        .into_owned();
+    serde_json::from_str(&json).context("invalid payload")
+}
+
+/// Apply the buttons clicked on an alert. The button value is the aggregation key.
+pub async fn handle<N: Notifier>(payload: Payload, aggregator: Arc<Aggregator<N>>) {
+    if payload.kind != "block_actions" {
+        log::debug!("ignoring slack interaction of type {}", payload.kind);
+        return;
+    }
+    let user = payload.user.label();
+    for action in &payload.actions {
+        let Some(key) = action.value.as_deref() else { continue };
+        let done = match action.action_id.as_str() {
+            "acknowledge" => aggregator.acknowledge(key, user).await,
+            "mute_1h" => mute(&aggregator, key, user, 60).await,
+            "mute_24h" => mute(&aggregator, key, user, 24 * 60).await,
+            "resolve" => aggregator.resolve(key, user).await,
+            _ => continue,
+        };
+        if done {
+            log::info!("{} by {} on {}", action.action_id, user, key);
+        } else {
+            log::warn!("{} by {} on {}: no open aggregate", action.action_id, user, key);
+        }
+    }
+}
+
+async fn mute<N: Notifier>(aggregator: &Aggregator<N>, key: &str, user: &str, minutes: i64) -> bool {
+    match aggregator.mute(key, user, minutes).await {
+        Ok(found) => found,
+        Err(e) => {
+            log::error!("failed to mute {}: {}", key, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_are_checked_against_secret_and_age() {
+        let verifier = Verifier::new("8f742231b10e8888abcd99yyyzzz85a5");
+        let body = b"payload=%7B%7D";
+        let signature = verifier.sign("1531420618", body);
+
+        assert!(verifier.verify("1531420618", &signature, body, 1531420618 + 60).is_ok());
+        assert!(verifier.verify("1531420618", &signature, b"payload=%7B%20%7D", 1531420618).is_err());
+        assert!(verifier.verify("1531420619", &signature, body, 1531420618).is_err());
+        assert!(verifier.verify("1531420618", &signature, body, 1531420618 + 301).is_err());
+        assert!(Verifier::new("other").verify("1531420618", &signature, body, 1531420618).is_err());
+        assert!(verifier.verify("1531420618", "v0=zz", body, 1531420618).is_err());
+    }
+
+    #[test]
+    fn parses_form_encoded_block_actions() {
+        let json = r#"{"type":"block_actions","user":{"id":"U1","username":"kari"},"actions":[{"action_id":"resolve","value":"helved/app|x y|1"},{"action_id":"button-action-2"}]}"#;
+        let body = format!("payload={}", urlencoding::encode(json));
+
+        let payload = parse(body.as_bytes()).unwrap();
+        assert_eq!(payload.kind, "block_actions");
+        assert_eq!(payload.user.label(), "kari");
+        assert_eq!(payload.actions[0].value.as_deref(), Some("helved/app|x y|1"));
+        assert!(payload.actions[1].value.is_none());
+    }
+}
@@ -1,28 +1,162 @@
-use std::{collections::HashMap};
+use std::{collections::{BTreeMap, HashMap, HashSet}, hash::{DefaultHasher, Hash, Hasher}, sync::{Arc, Mutex}};
 
 use anyhow::Result;
 use futures::{AsyncBufReadExt, StreamExt, TryStreamExt};
-use k8s_openapi::{api::core::v1::Pod};
+use k8s_openapi::{api::core::v1::{ContainerStatus, Pod}, jiff::Timestamp};
 use kube::{api::{Api, LogParams}, runtime::{watcher}, Client, ResourceExt };
 use tokio::{sync::mpsc::{Sender}, time::Duration, task::{AbortHandle}};
 
-use crate::model::Log;
+use crate::health::Health;
+use crate::metrics::METRICS;
+use crate::model::{Lifecycle, Log, Source};
+use crate::multiline::{Assembler, MultilineConfig};
+use crate::parser::{Parser, ParserConfig};
+use crate::rate::RateTracker;
+
+/// Waiting reasons that mean the container will not come up on its own.
+const ALERTING_WAITING_REASONS: &[&str] = &[
+    "CrashLoopBackOff",
+    "ImagePullBackOff",
+    "ErrImagePull",
+    "InvalidImageName",
+    "CreateContainerConfigError",
+    "CreateContainerError",
+    "RunContainerError",
+];
+
+/// Which pods to tail. An empty `namespaces` list means every namespace the
+/// service account can read.
+#[derive(Debug, Clone, Default)]
+pub struct WatchConfig {
+    pub namespaces: Vec<String>,
+    pub label_selector: Option<String>,
+    pub field_selector: Option<String>,
+    /// How many lines of a restarted container's previous instance to scan for errors.
+    /// Zero disables the scan.
+    pub previous_log_lines: i64,
+    /// Default log formats, overridable per container with pod annotations.
+    pub parsers: ParserConfig,
+    /// Default rules for joining multiline events, overridable per container.
+    pub multiline: MultilineConfig,
+    /// Counts every parsed line by level, for spike detection.
+    pub rates: Arc<RateTracker>,
+    /// Told when the initial pod list is done, and whether the watch loop still runs.
+    pub health: Arc<Health>,
+}
+
+impl WatchConfig {
+    fn watcher_config(&self) -> watcher::Config {
+        let mut wc = watcher::Config::default(); //.streaming_lists(); // krever feature WatchList i K8s
+        if let Some(labels) = &self.label_selector {
+            wc = wc.labels(labels);
+        }
+        if let Some(fields) = &self.field_selector {
+            wc = wc.fields(fields);
+        }
+        wc
+    }
+
+    /// Parser chain for a container, falling back to the defaults if the pod's
+    /// annotations are invalid.
+    fn parser(&self, annotations: &BTreeMap<String, String>, source: &Source) -> Arc<Parser> {
+        let parser = self.parsers.for_container(annotations, &source.container).unwrap_or_else(|e| {
+            log::warn!("Invalid log format annotations on {}: {}, using defaults", source, e);
+            self.parsers
+                .for_container(&BTreeMap::new(), &source.container)
+                .expect("default log formats are validated at startup")
+        });
+        Arc::new(parser)
+    }
+
+    fn multiline(&self, annotations: &BTreeMap<String, String>, source: &Source) -> MultilineConfig {
+        self.multiline.for_container(annotations, &source.container).unwrap_or_else(|e| {
+            log::warn!("Invalid multiline annotations on {}: {}, using defaults", source, e);
+            self.multiline.clone()
+        })
+    }
+}
 
 pub async fn watch_pods(
     client: Client,
-    namespace: &str,
-    tx: Sender<(Log, String, String)>,
+    config: &WatchConfig,
+    tx: Sender<(Log, Source)>,
 ) -> Result<()> {
-    let api: Api<Pod> = Api::namespaced(client, namespace);
-    let wc = watcher::Config::default(); //.streaming_lists(); // krever feature WatchList i K8s
-    let mut events = watcher(api.clone(), wc).boxed();
-    let mut log_tasks: HashMap<String, AbortHandle> = HashMap::new();
+    let wc = config.watcher_config();
+    let watched: Vec<Api<Pod>> = if config.namespaces.is_empty() {
+        vec![Api::all(client.clone())]
+    } else {
+        config.namespaces.iter().map(|ns| Api::namespaced(client.clone(), ns)).collect()
+    };
+    let watchers = watched.len();
+    // Tagged with the watcher they came from, so a watcher that restarts and lists again
+    // is not counted twice.
+    let mut events = futures::stream::select_all(
+        watched.into_iter().enumerate().map(|(i, api)| watcher(api, wc.clone()).map_ok(move |e| (i, e)).boxed())
+    );
+    let task = config.health.task("watcher");
+    let mut synced = HashSet::new();
+    let mut log_tasks: HashMap<String, (AbortHandle, SharedCursor)> = HashMap::new();
+    let mut snapshots: HashMap<String, ContainerSnapshot> = HashMap::new();
     let self_name = crate::env("NAIS_APP_NAME");
 
-    while let Some(event) = events.try_next().await? {
+    while let Some((watcher_index, event)) = events.try_next().await? {
+        let busy = task.busy();
         match event {
             watcher::Event::InitApply(pod) | watcher::Event::Apply(pod) => {
                 let pod_name = pod.name_any();
+                let namespace = pod.namespace().unwrap_or_default();
+                let annotations = pod.annotations().clone();
+
+                let statuses = pod.status.as_ref()
+                    .and_then(|s| s.container_statuses.as_ref())
+                    .map(|s| s.as_slice())
+                    .unwrap_or_default();
+                for status in statuses {
+                    if status.name == self_name { continue; }
+                    let snapshot_key = format!("{}/{}/{}", namespace, pod_name, status.name);
+                    let prev = snapshots.get(&snapshot_key);
+                    let restarted = prev.is_some_and(|p| status.restart_count > p.restart_count);
+                    let changes = match prev {
+                        Some(prev) => lifecycle_changes(prev, status),
+                        None => Vec::new(), // first sighting only sets the baseline
+                    };
+                    snapshots.insert(snapshot_key.clone(), ContainerSnapshot::from(status));
+                    let source = Source {
+                        namespace: namespace.clone(),
+                        pod: pod_name.clone(),
+                        container: status.name.clone(),
+                        previous: false,
+                    };
+
+                    if restarted && config.previous_log_lines > 0 {
+                        let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
+                        // Where the live stream was when the crashed instance's stream ended, so
+                        // lines it already forwarded before the crash are skipped.
+                        let cursor = log_tasks
+                            .get(&snapshot_key)
+                            .map(|(_, cursor)| cursor.lock().unwrap().previous_instance())
+                            .unwrap_or_default();
+                        let source = Source { previous: true, ..source.clone() };
+                        let tx_clone = tx.clone();
+                        let lines = config.previous_log_lines;
+                        let parser = config.parser(&annotations, &source);
+                        let assembler = Assembler::new(config.multiline(&annotations, &source));
+                        tokio::spawn(async move {
+                            if let Err(e) = scan_previous_logs(source, pods, lines, cursor, parser, assembler, tx_clone).await {
+                                log::warn!("Failed to read previous container logs: {}", e);
+                            }
+                        });
+                    }
+
+                    for (lifecycle, timestamp) in changes {
+                        log::info!("{}: {}", source, lifecycle.reason);
+                        // A full channel means the consumer is waiting on Slack, not that we are stuck.
+                        if busy.waiting(tx.send((Log::from_lifecycle(lifecycle, timestamp), source.clone()))).await.is_err() {
+                            log::info!("Log channel closed, stopping pod watcher");
+                            return Ok(());
+                        }
+                    }
+                }
 
                 if pod_phase(&pod) == "Running" { // && !log_tasks.contains_key(&pod_name) {
                     let containers = pod.spec
@@ -32,40 +166,59 @@ pub async fn watch_pods(
 
                     for container_name in containers {
                         if container_name == self_name { continue; }
-                        let task_key = format!("{}/{}", pod_name, container_name);
+                        let task_key = format!("{}/{}/{}", namespace, pod_name, container_name);
                         if !log_tasks.contains_key(&task_key) {
-                            let pods_clone = api.clone();
+                            let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
                             let tx_clone = tx.clone();
-                            let pod_name_clone = pod_name.clone();
+                            let source = Source {
+                                namespace: namespace.clone(),
+                                pod: pod_name.clone(),
+                                container: container_name,
+                                previous: false,
+                            };
+                            let parser = config.parser(&annotations, &source);
+                            let assembler = Assembler::new(config.multiline(&annotations, &source));
+                            let rates = config.rates.clone();
+                            let cursor = SharedCursor::default();
 
-                            let handle = tokio::spawn(async move {
-                                match watch_logs(container_name, pod_name_clone, pods_clone, tx_clone).await {
-                                    Ok(_) => (),
-                                    Err(e) => log::error!("Task error {}", e),
+                            let handle = tokio::spawn({
+                                let cursor = cursor.clone();
+                                async move {
+                                    match watch_logs(source, pods, cursor, parser, assembler, rates, tx_clone).await {
+                                        Ok(_) => (),
+                                        Err(e) => log::error!("Task error {}", e),
+                                    }
                                 }
                             });
-                            log_tasks.insert(task_key.clone(), handle.abort_handle());
+                            log_tasks.insert(task_key.clone(), (handle.abort_handle(), cursor));
                             log::info!("started log task for {}", task_key);
                         }
                     }
                 }
             }
             watcher::Event::Delete(pod) => {
-                let pod_name = pod.name_any();
+                let prefix = format!("{}/{}/", pod.namespace().unwrap_or_default(), pod.name_any());
                 let keys_to_remove: Vec<String> = log_tasks
                     .keys()
-                    .filter(|k| k.starts_with(&format!("{}/", pod_name)))
+                    .filter(|k| k.starts_with(&prefix))
                     .cloned()
                     .collect();
 
                 for key in keys_to_remove {
-                    if let Some(handle) = log_tasks.remove(&key) {
+                    if let Some((handle, _)) = log_tasks.remove(&key) {
                         handle.abort();
                         log::info!("aborted log task for {}", key);
                     }
                 }
+                snapshots.retain(|k, _| !k.starts_with(&prefix));
             },
-            watcher::Event::Init | watcher::Event::InitDone => {}
+            watcher::Event::InitDone => {
+                if synced.insert(watcher_index) && synced.len() == watchers {
+                    log::info!("initial pod list done");
+                    config.health.synced();
+                }
+            }
+            watcher::Event::Init => {}
         }
     }
 
@@ -79,56 +232,219 @@ fn pod_phase(pod: &Pod) -> &str {
         .unwrap_or("unknown")
 }
 
+/// What we remember about a container between Apply events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ContainerSnapshot {
+    restart_count: i32,
+    waiting_reason: Option<String>,
+    ready: bool,
+}
+
+impl From<&ContainerStatus> for ContainerSnapshot {
+    fn from(status: &ContainerStatus) -> Self {
+        ContainerSnapshot {
+            restart_count: status.restart_count,
+            waiting_reason: status.state.as_ref()
+                .and_then(|s| s.waiting.as_ref())
+                .and_then(|w| w.reason.clone()),
+            ready: status.ready,
+        }
+    }
+}
+
+/// Diff a container status against the previous snapshot. Returns one lifecycle event per
+/// reason that appeared since last time, with the time it happened if the status knows it.
+fn lifecycle_changes(prev: &ContainerSnapshot, status: &ContainerStatus) -> Vec<(Lifecycle, Option<String>)> {
+    let terminated = status.last_state.as_ref().and_then(|s| s.terminated.as_ref());
+    let event = |reason: String| Lifecycle {
+        reason,
+        restart_count: status.restart_count,
+        exit_code: terminated.map(|t| t.exit_code),
+        termination_reason: terminated.and_then(|t| t.reason.clone()),
+        termination_message: terminated.and_then(|t| t.message.clone()),
+    };
+    let finished_at = terminated
+        .and_then(|t| t.finished_at.as_ref())
+        .map(|t| t.0.to_string());
+
+    let mut changes = Vec::new();
+    let current = ContainerSnapshot::from(status);
+
+    if current.restart_count > prev.restart_count {
+        let reason = terminated
+            .and_then(|t| t.reason.clone())
+            .unwrap_or_else(|| "Restarted".to_string());
+        changes.push((event(reason), finished_at));
+    }
+
+    if let Some(reason) = &current.waiting_reason
+        && ALERTING_WAITING_REASONS.contains(&reason.as_str())
+        && prev.waiting_reason.as_ref() != Some(reason)
+    {
+        changes.push((event(reason.clone()), None));
+    }
+
+    let running = status.state.as_ref().is_some_and(|s| s.running.is_some());
+    if prev.ready && !current.ready && running {
+        changes.push((event("NotReady".to_string()), None));
+    }
+
+    changes
+}
+
+/// A container's live stream cursor, shared with the watcher so the scan of a previous
+/// instance can dedupe against it.
+type SharedCursor = Arc<Mutex<LiveCursor>>;
+
+#[derive(Default)]
+struct LiveCursor {
+    current: StreamCursor,
+    /// Copy of `current` from when the last stream ended. Once the stream has reconnected
+    /// to the new instance `current` is past every line of the old one.
+    ended: Option<StreamCursor>,
+}
+
+impl LiveCursor {
+    fn stream_ended(&mut self) {
+        self.ended = Some(self.current.clone());
+    }
+
+    /// Cursor for scanning the instance before a restart. Its own copy, so the scan never
+    /// moves the live stream.
+    fn previous_instance(&self) -> StreamCursor {
+        self.ended.as_ref().unwrap_or(&self.current).clone()
+    }
+}
+
+/// Position in a followed log stream, so a reconnect can resume where the previous
+/// stream ended instead of skipping whatever was logged in between.
+#[derive(Clone)]
+struct StreamCursor {
+    started: Timestamp,
+    last: Option<Timestamp>,
+    seen_at_last: HashSet<u64>,
+}
+
+impl Default for StreamCursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamCursor {
+    fn new() -> Self {
+        StreamCursor { started: Timestamp::now(), last: None, seen_at_last: HashSet::new() }
+    }
+
+    /// Where to resume from. The API only takes whole seconds, so this is truncated and
+    /// the overlap is filtered out again by `advance`.
+    fn since_time(&self) -> Timestamp {
+        let last = self.last.unwrap_or(self.started);
+        Timestamp::from_second(last.as_second()).unwrap_or(last)
+    }
+
+    /// Strip the timestamp prefix from a line and move the cursor past it. Returns `None`
+    /// for lines already seen by an earlier stream.
+    fn advance<'a>(&mut self, line: &'a str) -> Option<&'a str> {
+        let Some((ts, rest)) = line.split_once(' ') else { return Some(line) };
+        let Ok(ts) = ts.parse::<Timestamp>() else { return Some(line) };
+
+        let mut h = DefaultHasher::new();
+        line.hash(&mut h);
+        let hash = h.finish();
+
+        match self.last {
+            Some(last) if ts < last => return None,
+            Some(last) if ts == last => {
+                if !self.seen_at_last.insert(hash) {
+                    return None;
+                }
+            }
+            _ => {
+                self.last = Some(ts);
+                self.seen_at_last.clear();
+                self.seen_at_last.insert(hash);
+            }
+        }
+        Some(rest)
+    }
+}
+
 async fn watch_logs(
-    container_name: String,
-    pod_name: String,
+    source: Source,
     pods: Api<Pod>,
-    tx: Sender<(Log, String, String)>,
+    cursor: SharedCursor,
+    parser: Arc<Parser>,
+    mut assembler: Assembler,
+    rates: Arc<RateTracker>,
+    tx: Sender<(Log, Source)>,
 ) -> Result<()> {
+    let mut reconnect = false;
+
     loop {
+        // First connect starts at the tail, later ones resume from the last line seen.
+        let (tail_lines, since_time) = if reconnect {
+            METRICS.stream_reconnect(&source);
+            (None, Some(cursor.lock().unwrap().current.since_time()))
+        } else {
+            (Some(0), None)
+        };
+        reconnect = true;
         let params = LogParams {
-            container: Some(container_name.clone()),
-            tail_lines: Some(0), 
-            timestamps: false, 
+            container: Some(source.container.clone()),
+            tail_lines,
+            since_time,
+            timestamps: true,
             follow: true,
             ..LogParams::default()
         };
 
-        let task_name = format!("{}/{}", pod_name, container_name);
+        let task_name = source.to_string();
         log::info!("starting log stream for {}", task_name);
 
-        match pods.log_stream(&pod_name, &params).await {
+        match pods.log_stream(&source.pod, &params).await {
             Ok(logs) => {
                 let mut lines = logs.lines();
 
-                while let Some(line_result) = lines.next().await {
-                    match line_result {
-                        Ok(line) => {
-                            if let Some(json_start_idx) = line.find('{') {
-                                let json_part = &line[json_start_idx..];
-                                match serde_json::from_str::<Log>(json_part) {
-                                    Ok(log) => {
-                                        if log.is_error() && tx.send((log, container_name.clone(), pod_name.clone())).await.is_err() { 
-                                            log::info!("Log channel closed, stopping log task for {}", task_name);
-                                            return Ok(());
-                                        }
-                                    }
-                                    Err(e) => log::error!("JSON parse error on {}: {}", task_name, e),
+                loop {
+                    // A pending multiline event is emitted once the stream goes quiet.
+                    let next = match assembler.deadline() {
+                        Some(deadline) => match tokio::time::timeout_at(deadline, lines.next()).await {
+                            Ok(next) => next,
+                            Err(_) => {
+                                if !forward(assembler.flush(), &parser, Some(&rates), &source, &tx).await {
+                                    return Ok(());
                                 }
+                                continue;
+                            }
+                        },
+                        None => lines.next().await,
+                    };
+                    match next {
+                        Some(Ok(line)) => {
+                            let Some(line) = cursor.lock().unwrap().current.advance(&line) else { continue };
+                            METRICS.line_read(&source);
+                            if !forward(assembler.push(line), &parser, Some(&rates), &source, &tx).await {
+                                return Ok(());
                             }
                         }
-                        Err(e) => {
+                        Some(Err(e)) => {
                             log::warn!("Error reading log line from {}: {}", task_name, e);
                             break;
                         }
+                        None => break,
                     }
                 }
+                cursor.lock().unwrap().stream_ended();
+                if !forward(assembler.flush(), &parser, Some(&rates), &source, &tx).await {
+                    return Ok(());
+                }
                 log::info!("Log stream ended for {}. Retrying...", task_name);
                 tokio::time::sleep(Duration::from_secs(2)).await;
             }
             Err(e) => {
                 if is_not_found(&e) {
-                    log::info!("Pod {} not found (likely deleted), stopping the log task.", pod_name);
+                    log::info!("Pod {}/{} not found (likely deleted), stopping the log task.", source.namespace, source.pod);
                     return Ok(());
                 }
 
@@ -139,6 +455,77 @@ async fn watch_logs(
     }
 }
 
+/// Read the tail of the container instance that just died, so the errors leading up to
+/// the crash are not lost while the live stream reconnects to the new instance. Lines the
+/// live stream already forwarded are skipped through `cursor`.
+async fn scan_previous_logs(
+    source: Source,
+    pods: Api<Pod>,
+    lines: i64,
+    mut cursor: StreamCursor,
+    parser: Arc<Parser>,
+    mut assembler: Assembler,
+    tx: Sender<(Log, Source)>,
+) -> Result<()> {
+    let params = LogParams {
+        container: Some(source.container.clone()),
+        previous: true,
+        tail_lines: Some(lines),
+        timestamps: true,
+        ..LogParams::default()
+    };
+    log::info!("scanning the last {} lines of {}", lines, source);
+    let logs = pods.logs(&source.pod, &params).await?;
+
+    // Not counted towards rates: these lines were logged before the restart.
+    for line in logs.lines() {
+        let Some(line) = cursor.advance(line) else { continue };
+        METRICS.line_read(&source);
+        if !forward(assembler.push(line), &parser, None, &source, &tx).await {
+            return Ok(());
+        }
+    }
+    forward(assembler.flush(), &parser, None, &source, &tx).await;
+    Ok(())
+}
+
+/// Parse an assembled event, count it, and send it on if it is alertable. Returns `false`
+/// once the channel is closed and the stream should stop.
+async fn forward(
+    event: Option<String>,
+    parser: &Parser,
+    rates: Option<&RateTracker>,
+    source: &Source,
+    tx: &Sender<(Log, Source)>,
+) -> bool {
+    let Some(log) = event.and_then(|e| parse_line(&e, parser, source)) else {
+        return true;
+    };
+    if let Some(rates) = rates {
+        rates.record(source, log.level());
+    }
+    if !parser.alerts_on(&log) {
+        return true;
+    }
+    if tx.send((log, source.clone())).await.is_err() {
+        log::info!("Log channel closed, stopping log task for {}", source);
+        return false;
+    }
+    true
+}
+
+/// Parse a raw container log event, logging lines that look malformed.
+fn parse_line(line: &str, parser: &Parser, source: &Source) -> Option<Log> {
+    match parser.parse(line) {
+        Ok(log) => log,
+        Err(e) => {
+            METRICS.parse_error(source);
+            log::error!("{:#} on {}", e, source);
+            None
+        }
+    }
+}
+
 fn is_not_found(e: &kube::Error) -> bool {
     if let kube::Error::Api(err) = e {
         return err.code == 404;
@@ -146,3 +533,87 @@ fn is_not_found(e: &kube::Error) -> bool {
     false
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::core::v1::{ContainerState, ContainerStateTerminated, ContainerStateWaiting};
+
+    fn status(restarts: i32, waiting: Option<&str>, ready: bool) -> ContainerStatus {
+        ContainerStatus {
+            name: "app".into(),
+            restart_count: restarts,
+            ready,
+            state: Some(ContainerState {
+                waiting: waiting.map(|r| ContainerStateWaiting { reason: Some(r.into()), message: None }),
+                ..ContainerState::default()
+            }),
+            last_state: Some(ContainerState {
+                terminated: Some(ContainerStateTerminated {
+                    exit_code: 137,
+                    reason: Some("OOMKilled".into()),
+                    ..ContainerStateTerminated::default()
+                }),
+                ..ContainerState::default()
+            }),
+            ..ContainerStatus::default()
+        }
+    }
+
+    #[test]
+    fn restart_and_crashloop_are_reported_once() {
+        let prev = ContainerSnapshot::from(&status(0, None, true));
+        let next = status(1, Some("CrashLoopBackOff"), false);
+        let reasons: Vec<String> = lifecycle_changes(&prev, &next).into_iter().map(|(l, _)| l.reason).collect();
+        assert_eq!(reasons, vec!["OOMKilled", "CrashLoopBackOff"]);
+
+        let prev = ContainerSnapshot::from(&next);
+        assert!(lifecycle_changes(&prev, &next).is_empty());
+    }
+
+    #[test]
+    fn cursor_skips_lines_replayed_after_reconnect() {
+        let mut cursor = StreamCursor::new();
+        assert_eq!(cursor.advance("2025-05-19T08:00:00.100Z a"), Some("a"));
+        assert_eq!(cursor.advance("2025-05-19T08:00:00.200Z b"), Some("b"));
+        assert_eq!(cursor.since_time().to_string(), "2025-05-19T08:00:00Z");
+
+        // Resumed stream replays the whole second.
+        assert_eq!(cursor.advance("2025-05-19T08:00:00.100Z a"), None);
+        assert_eq!(cursor.advance("2025-05-19T08:00:00.200Z b"), None);
+        assert_eq!(cursor.advance("2025-05-19T08:00:00.200Z c"), Some("c"));
+        assert_eq!(cursor.advance("2025-05-19T08:00:01.000Z d"), Some("d"));
+        assert_eq!(cursor.advance("no timestamp"), Some("no timestamp"));
+    }
+
+    #[test]
+    fn previous_scan_skips_what_the_live_stream_forwarded() {
+        let mut live = LiveCursor::default();
+        assert_eq!(live.current.advance("2025-05-19T08:00:00.100Z ERROR a"), Some("ERROR a"));
+        assert_eq!(live.current.advance("2025-05-19T08:00:01.000Z ERROR b"), Some("ERROR b"));
+        live.stream_ended();
+
+        // The stream reconnects to the new instance before the scan gets to run.
+        assert_eq!(live.current.advance("2025-05-19T08:00:05.000Z INFO started"), Some("INFO started"));
+        let mut cursor = live.previous_instance();
+
+        // The previous instance's tail overlaps with what the stream read before the crash.
+        let previous = [
+            "2025-05-19T07:59:59.000Z ERROR before the stream connected",
+            "2025-05-19T08:00:00.100Z ERROR a",
+            "2025-05-19T08:00:01.000Z ERROR b",
+            "2025-05-19T08:00:01.500Z ERROR c",
+        ];
+        let scanned: Vec<&str> = previous.iter().filter_map(|l| cursor.advance(l)).collect();
+        assert_eq!(scanned, vec!["ERROR c"]);
+        assert_eq!(live.current.since_time().to_string(), "2025-05-19T08:00:05Z");
+    }
+
+    #[test]
+    fn restart_carries_termination_details() {
+        let prev = ContainerSnapshot::from(&status(2, None, true));
+        let (lifecycle, _) = lifecycle_changes(&prev, &status(3, None, true)).remove(0);
+        assert_eq!(lifecycle.restart_count, 3);
+        assert_eq!(lifecycle.exit_code, Some(137));
+        assert_eq!(lifecycle.termination_reason.as_deref(), Some("OOMKilled"));
+    }
+}
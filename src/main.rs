@@ -5,10 +5,25 @@ use log4rs::{append::console::ConsoleAppender, config::*, encode::json::JsonEnco
 use tokio::{join, sync::mpsc};
 
 mod aggregator;
+mod dashboard;
+mod health;
+mod history;
+mod http;
+mod interact;
 mod k8s;
+mod metrics;
 mod model;
+mod multiline;
+mod notifier;
+mod parser;
 mod probe;
+mod rate;
+mod redact;
+mod routing;
+mod silence;
 mod slack;
+mod state;
+mod threshold;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -19,38 +34,114 @@ async fn main() -> Result<()> {
     init_logger();
 
     let client = kube::Client::try_default().await?;
-    let namespace = env("NAIS_NAMESPACE");
-    let (tx, mut rx) = mpsc::channel::<(model::Log, String, String)>(100);
-
-    let slack = Arc::new(slack::Slack::default());
-    let window_seconds: i64 = env_or("AGGREGATE_WINDOW_SECONDS", 600);
-    let edit_throttle_ms: u64 = env_or("AGGREGATE_EDIT_THROTTLE_MS", 5000);
-    let aggregator = aggregator::Aggregator::new(slack.clone(), window_seconds, edit_throttle_ms);
-    let _flush_handle = aggregator.clone().spawn_flush();
+    match env_opt("NOTIFIER").as_deref() {
+        Some("stdout") => run(client, notifier::Stdout::default()).await,
+        _ => run(client, slack::Slack::default()).await,
+    }
+}
 
+async fn run<N: notifier::Notifier>(client: kube::Client, notifier: N) -> Result<()> {
+    let health = health::Health::new(std::time::Duration::from_secs(env_or("LIVENESS_DEADLINE_SECONDS", 300)));
+    let notifier = Arc::new(notifier);
+    let _check_handle = health
+        .clone()
+        .spawn_notifier_check(notifier.clone(), std::time::Duration::from_secs(env_or("NOTIFIER_CHECK_SECONDS", 300)));
+    let rates = Arc::new(rate::RateTracker::new(rate::RateConfig {
+        levels: env_list("RATE_LEVELS", "WARN"),
+        bucket: std::time::Duration::from_secs(env_or("RATE_BUCKET_SECONDS", 60)),
+        baseline_buckets: env_or("RATE_BASELINE_BUCKETS", 30),
+        factor: env_or("RATE_SPIKE_FACTOR", 10.0),
+        min_count: env_or("RATE_MIN_COUNT", 20),
+    }));
+    let watch = k8s::WatchConfig {
+        namespaces: watched_namespaces()?,
+        label_selector: env_opt("WATCH_LABEL_SELECTOR"),
+        field_selector: env_opt("WATCH_FIELD_SELECTOR"),
+        previous_log_lines: env_or("PREVIOUS_LOG_LINES", 200),
+        parsers: parser::ParserConfig::from_env()?,
+        multiline: multiline::MultilineConfig::from_env()?,
+        rates: rates.clone(),
+        health: health.clone(),
+    };
+    let (tx, mut rx) = mpsc::channel::<(model::Log, model::Source)>(100);
+    let _rate_handle = rates.spawn(tx.clone());
+
+    let config = aggregator::Config {
+        cluster: env("NAIS_CLUSTER_NAME"),
+        window_seconds: env_or("AGGREGATE_WINDOW_SECONDS", 600),
+        edit_throttle_ms: env_or("AGGREGATE_EDIT_THROTTLE_MS", 5000),
+        thread_variants_max: env_or("THREAD_VARIANTS_MAX", 20),
+        thread_batch_size: env_or("THREAD_BATCH_SIZE", 5),
+        fingerprint: model::Fingerprint {
+            stack_frames: env_or("FINGERPRINT_STACK_FRAMES", 5),
+            in_app: env_list("IN_APP_PACKAGES", "no.nav"),
+        },
+        thresholds: threshold::Thresholds::from_env()?,
+        slack_workspace_url: env_opt("SLACK_WORKSPACE_URL"),
+    };
+    let persist_interval_seconds: u64 = env_or("STATE_PERSIST_INTERVAL_SECONDS", 10);
+    let store = state::from_env(&client);
+    let router = routing::Router::from_env()?;
+    let aggregator = aggregator::Aggregator::new(notifier, router, config, store);
+    aggregator.restore().await;
+    let _flush_handle = aggregator.clone().spawn_flush(health.clone());
+    let _persist_handle = aggregator
+        .clone()
+        .spawn_persist(std::time::Duration::from_secs(persist_interval_seconds));
+
+    let redactor = redact::Redactor::from_env()?;
     let log_consumer = {
         let aggregator = aggregator.clone();
+        let health = health.clone();
         tokio::spawn(async move {
-            while let Some((log, container_name, pod_name)) = rx.recv().await {
-                log::info!("found {:?}", &log);
-                aggregator.ingest(log, container_name, pod_name).await;
+            let task = health.task("consumer");
+            while let Some((mut log, source)) = rx.recv().await {
+                task.run(async {
+                    log.redact(&redactor);
+                    log::info!("found {:?} in {}", &log, &source);
+                    aggregator.ingest(log, source).await;
+                })
+                .await;
             }
         })
     };
 
-    let pod_controller = k8s::watch_pods(client, &namespace, tx);
-    let health_probe = probe::health_check_server();
-
-    let (consumer_res, controller_res, health_res) =
-        join!(log_consumer, pod_controller, health_probe);
+    let server_state = probe::State {
+        aggregator: aggregator.clone(),
+        health,
+        queue: tx.downgrade(),
+        verifier: interact::Verifier::from_env(),
+    };
+    let pod_controller = k8s::watch_pods(client, &watch, tx);
+    let health_probe = probe::health_check_server(server_state, shutdown_signal());
+    let tasks = async {
+        let (consumer_res, controller_res) = join!(log_consumer, pod_controller);
+        controller_res?;
+        consumer_res?;
+        Ok::<(), anyhow::Error>(())
+    };
 
-    controller_res?;
-    health_res?;
-    consumer_res?;
+    // The server only returns once it has drained after a shutdown signal.
+    tokio::select! {
+        res = tasks => res?,
+        res = health_probe => res?,
+    }
+    aggregator.persist().await;
 
     Ok(())
 }
 
+/// Resolves on SIGTERM (what Kubernetes sends) or Ctrl-C.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    log::info!("shutdown signal received");
+}
+
 pub fn env(env: &str) -> String {
     std::env::var(env).unwrap_or_else(|_| panic!("env var {} missing", env))
 }
@@ -62,6 +153,42 @@ fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
         .unwrap_or(default)
 }
 
+fn env_opt(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|v| !v.trim().is_empty())
+}
+
+/// Comma separated list, with `default` used when the variable is unset. Set it empty
+/// for an empty list.
+fn env_list(key: &str, default: &str) -> Vec<String> {
+    std::env::var(key)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// `WATCH_NAMESPACES` is a comma separated list, or `*` for every namespace we can read.
+/// Defaults to the namespace we are deployed in. A list without any namespace in it is an
+/// error rather than a quiet switch to every namespace.
+fn watched_namespaces() -> Result<Vec<String>> {
+    let Ok(value) = std::env::var("WATCH_NAMESPACES") else {
+        return Ok(vec![env("NAIS_NAMESPACE")]);
+    };
+    if value.trim() == "*" {
+        return Ok(Vec::new());
+    }
+    let namespaces: Vec<String> = value
+        .split(',')
+        .map(|ns| ns.trim().to_string())
+        .filter(|ns| !ns.is_empty())
+        .collect();
+    if namespaces.is_empty() {
+        anyhow::bail!("WATCH_NAMESPACES {:?} names no namespace, use * to watch all of them", value);
+    }
+    Ok(namespaces)
+}
+
 fn init_logger() {
     let stdout = ConsoleAppender::builder()
         .encoder(Box::new(JsonEncoder::new()))
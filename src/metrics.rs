@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use crate::model::Source;
+
+/// Upper bounds of the Slack latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Process wide counters, rendered in the Prometheus text format on `/metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+pub struct Metrics {
+    lines_read: Mutex<BTreeMap<Labels, u64>>,
+    parse_errors: Mutex<BTreeMap<Labels, u64>>,
+    stream_reconnects: Mutex<BTreeMap<Labels, u64>>,
+    /// Per aggregation key, dropped when the aggregate closes so keys don't pile up.
+    errors_ingested: Mutex<BTreeMap<Labels, u64>>,
+    slack_failures: Mutex<BTreeMap<Labels, u64>>,
+    slack_latency: Mutex<BTreeMap<Labels, Histogram>>,
+}
+
+#[derive(Default, Clone)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+fn container(source: &Source) -> Labels {
+    vec![("namespace", source.namespace.clone()), ("container", source.container.clone())]
+}
+
+fn inc(series: &Mutex<BTreeMap<Labels, u64>>, labels: Labels) {
+    *series.lock().unwrap().entry(labels).or_default() += 1;
+}
+
+impl Metrics {
+    pub fn line_read(&self, source: &Source) {
+        inc(&self.lines_read, container(source));
+    }
+
+    pub fn parse_error(&self, source: &Source) {
+        inc(&self.parse_errors, container(source));
+    }
+
+    pub fn stream_reconnect(&self, source: &Source) {
+        inc(&self.stream_reconnects, container(source));
+    }
+
+    pub fn error_ingested(&self, key: &str) {
+        inc(&self.errors_ingested, vec![("key", key.to_string())]);
+    }
+
+    /// Drop the series of an aggregate that closed.
+    pub fn aggregate_closed(&self, key: &str) {
+        self.errors_ingested.lock().unwrap().remove(&vec![("key", key.to_string())]);
+    }
+
+    pub fn slack_call(&self, method: &str, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut latency = self.slack_latency.lock().unwrap();
+        let histogram = latency.entry(vec![("method", method.to_string())]).or_default();
+        for (bucket, le) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= le {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    /// A failed Slack call attempt. `error` is Slack's error code, or `http_<status>`
+    /// and `request_failed` when there was no API answer.
+    pub fn slack_failure(&self, method: &str, error: &str) {
+        inc(&self.slack_failures, vec![("method", method.to_string()), ("error", error.to_string())]);
+    }
+
+    /// Everything in the text exposition format. Gauges are read by the caller at scrape time.
+    pub fn render(&self, active_aggregates: usize, queue_depth: usize) -> String {
+        let mut out = String::new();
+        counter(&mut out, "logs_lines_read_total", "Log lines read, per container.", &self.lines_read);
+        counter(&mut out, "logs_parse_errors_total", "Log events that failed to parse, per container.", &self.parse_errors);
+        counter(&mut out, "logs_stream_reconnects_total", "Log streams reopened after they ended or failed, per container.", &self.stream_reconnects);
+        counter(&mut out, "logs_errors_ingested_total", "Errors added to an open aggregate, per aggregation key.", &self.errors_ingested);
+        gauge(&mut out, "logs_active_aggregates", "Aggregates currently open.", active_aggregates);
+        gauge(&mut out, "logs_queue_depth", "Log events waiting in the channel to the aggregator.", queue_depth);
+        counter(&mut out, "logs_slack_failures_total", "Failed Slack call attempts, per method and error code.", &self.slack_failures);
+
+        let name = "logs_slack_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Latency of Slack calls, per method.\n# TYPE {name} histogram");
+        for (labels, histogram) in self.slack_latency.lock().unwrap().iter() {
+            for (count, le) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let mut labels = labels.clone();
+                labels.push(("le", le.to_string()));
+                let _ = writeln!(out, "{name}_bucket{} {count}", format_labels(&labels));
+            }
+            let mut inf = labels.clone();
+            inf.push(("le", "+Inf".into()));
+            let _ = writeln!(out, "{name}_bucket{} {}", format_labels(&inf), histogram.count);
+            let _ = writeln!(out, "{name}_sum{} {}", format_labels(labels), histogram.sum);
+            let _ = writeln!(out, "{name}_count{} {}", format_labels(labels), histogram.count);
+        }
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, series: &Mutex<BTreeMap<Labels, u64>>) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+    for (labels, value) in series.lock().unwrap().iter() {
+        let _ = writeln!(out, "{name}{} {value}", format_labels(labels));
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}");
+}
+
+fn format_labels(labels: &[(&'static str, String)]) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text_format() {
+        let metrics = Metrics::default();
+        let source = Source { namespace: "helved".into(), pod: "app-1".into(), container: "app".into(), previous: false };
+        metrics.line_read(&source);
+        metrics.line_read(&source);
+        metrics.error_ingested("helved/app|\"x\"|1");
+        metrics.error_ingested("helved/app||2");
+        metrics.aggregate_closed("helved/app||2");
+        metrics.slack_call("chat.update", Duration::from_millis(300));
+        metrics.slack_failure("chat.update", "channel_not_found");
+
+        let text = metrics.render(3, 7);
+        assert!(text.contains("logs_lines_read_total{namespace=\"helved\",container=\"app\"} 2\n"));
+        assert!(text.contains("logs_errors_ingested_total{key=\"helved/app|\\\"x\\\"|1\"} 1\n"));
+        assert!(!text.contains("helved/app||2"));
+        assert!(text.contains("logs_active_aggregates 3\n"));
+        assert!(text.contains("logs_queue_depth 7\n"));
+        assert!(text.contains("logs_slack_request_duration_seconds_bucket{method=\"chat.update\",le=\"0.25\"} 0\n"));
+        assert!(text.contains("logs_slack_request_duration_seconds_bucket{method=\"chat.update\",le=\"0.5\"} 1\n"));
+        assert!(text.contains("logs_slack_request_duration_seconds_bucket{method=\"chat.update\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("logs_slack_failures_total{method=\"chat.update\",error=\"channel_not_found\"} 1\n"));
+    }
+}
@@ -1,11 +1,68 @@
 use chrono::{DateTime, Duration, Utc};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::hash_map::DefaultHasher;
 use std::collections::HashSet;
-use std::hash::{Hash, Hasher};
+use std::fmt;
 
-#[derive(Deserialize, Debug, Clone)]
+/// The container a log line was read from. `previous` is set for lines read back from
+/// the instance that ran before the latest restart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Source {
+    pub namespace: String,
+    pub pod: String,
+    pub container: String,
+    pub previous: bool,
+}
+
+impl Source {
+    /// Pod name as shown in alerts.
+    pub fn pod_label(&self) -> String {
+        if self.previous {
+            format!("{} (previous instance)", self.pod)
+        } else {
+            self.pod.clone()
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.namespace, self.pod, self.container)?;
+        if self.previous {
+            write!(f, " (previous)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A container state change read from the pod status rather than from the logs,
+/// e.g. an OOMKill or a CrashLoopBackOff.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Lifecycle {
+    pub reason: String,
+    pub restart_count: i32,
+    pub exit_code: Option<i32>,
+    pub termination_reason: Option<String>,
+    pub termination_message: Option<String>,
+}
+
+/// A jump in how often a container logs at some level, detected by `RateTracker` rather
+/// than read from a single log line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Spike {
+    pub level: String,
+    pub bucket_seconds: u64,
+    pub count: u64,
+    /// Median count per bucket before the spike.
+    pub baseline: f64,
+    /// Counts per bucket, oldest first, ending with the spiking one.
+    pub history: Vec<u64>,
+    /// Pods that logged at the level during the spiking bucket.
+    #[serde(default)]
+    pub pods: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[allow(dead_code)]
 pub struct Log {
     level: String,
@@ -17,17 +74,170 @@ pub struct Log {
     span_id: Option<String>,
     #[serde(rename = "HOSTNAME")]
     hostname: Option<String>,
+    stack_trace: Option<String>,
+    /// A class name string, or an object with the class under `class` or `type`.
+    exception: Option<serde_json::Value>,
+    thread_name: Option<String>,
+    #[serde(rename = "error.type")]
+    error_type: Option<String>,
+    /// Only ever set by us; serialized so persisted lifecycle samples survive a restart.
+    #[serde(rename = "_lifecycle", default, skip_serializing_if = "Option::is_none")]
+    lifecycle: Option<Lifecycle>,
+    /// Only ever set by us, like `lifecycle`.
+    #[serde(rename = "_spike", default, skip_serializing_if = "Option::is_none")]
+    spike: Option<Spike>,
 }
 
 impl Log {
-    pub fn is_error(&self) -> bool {
-        self.level == "ERROR"
+    /// Synthetic ERROR entry for a container state change, so it can go through the
+    /// same aggregation and rendering as regular log lines.
+    pub fn from_lifecycle(lifecycle: Lifecycle, timestamp: Option<String>) -> Self {
+        let mut message = format!("{} (restarts: {})", lifecycle.reason, lifecycle.restart_count);
+        if let Some(code) = lifecycle.exit_code {
+            message.push_str(&format!("\nexit code: {code}"));
+        }
+        if let Some(reason) = &lifecycle.termination_reason {
+            message.push_str(&format!("\ntermination reason: {reason}"));
+        }
+        if let Some(msg) = lifecycle.termination_message.as_deref().filter(|m| !m.is_empty()) {
+            message.push_str(&format!("\nlast termination message: {msg}"));
+        }
+        Log {
+            level: "ERROR".into(),
+            timestamp,
+            logger_name: Some("kubernetes".into()),
+            message,
+            trace_id: None,
+            span_id: None,
+            hostname: None,
+            stack_trace: None,
+            exception: None,
+            thread_name: None,
+            error_type: None,
+            lifecycle: Some(lifecycle),
+            spike: None,
+        }
+    }
+
+    /// Synthetic entry for a rate spike. The message stays the same for every spiking
+    /// bucket so the aggregate doesn't collect a variant per bucket; the numbers are in
+    /// the chart.
+    pub fn from_spike(spike: Spike) -> Self {
+        Log {
+            level: spike.level.clone(),
+            timestamp: None,
+            logger_name: Some("rate".into()),
+            message: format!("Spike in {} logs", spike.level),
+            trace_id: None,
+            span_id: None,
+            hostname: None,
+            stack_trace: None,
+            exception: None,
+            thread_name: None,
+            error_type: None,
+            lifecycle: None,
+            spike: Some(spike),
+        }
+    }
+
+    /// Scrub everything that can carry free text. Must run before the log is aggregated
+    /// or written anywhere.
+    pub fn redact(&mut self, redactor: &crate::redact::Redactor) {
+        self.message = redactor.redact(&self.message);
+        // Thread names often carry a request or user id; the others are redacted for good measure,
+        // since they reach Slack and the API as well.
+        for field in [&mut self.stack_trace, &mut self.thread_name, &mut self.logger_name, &mut self.error_type] {
+            if let Some(value) = field.as_mut() {
+                *value = redactor.redact(value);
+            }
+        }
+        // logstash-logback-encoder can emit it as an object with its own message.
+        if let Some(exception) = self.exception.as_mut() {
+            redact_json(exception, redactor);
+        }
+        if let Some(msg) = self.lifecycle.as_mut().and_then(|l| l.termination_message.as_mut()) {
+            *msg = redactor.redact(msg);
+        }
+    }
+
+    /// Cut `message` and `stack_trace` down to `max` bytes each, for storing the sample.
+    pub fn truncate_text(&mut self, max: usize) {
+        if self.message.len() > max {
+            self.message = truncate(&self.message, max);
+        }
+        if let Some(trace) = self.stack_trace.as_mut().filter(|t| t.len() > max) {
+            *trace = truncate(trace, max);
+        }
+    }
+
+    pub fn lifecycle(&self) -> Option<&Lifecycle> {
+        self.lifecycle.as_ref()
+    }
+
+    pub fn spike(&self) -> Option<&Spike> {
+        self.spike.as_ref()
+    }
+
+    pub fn level(&self) -> &str {
+        &self.level
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
     }
 
     pub fn logger_name(&self) -> Option<&str> {
         self.logger_name.as_deref()
     }
 
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread_name.as_deref()
+    }
+
+    pub fn stack_trace(&self) -> Option<&str> {
+        self.stack_trace.as_deref().filter(|s| !s.trim().is_empty())
+    }
+
+    /// Exception class from `error.type`, `exception`, or the first line of the stack trace.
+    pub fn exception_class(&self) -> Option<&str> {
+        if let Some(t) = self.error_type.as_deref().filter(|t| !t.is_empty()) {
+            return Some(t);
+        }
+        match &self.exception {
+            Some(serde_json::Value::String(s)) if !s.is_empty() => return Some(class_name(s)),
+            Some(serde_json::Value::Object(o)) => {
+                if let Some(c) = o.get("class").or_else(|| o.get("type")).and_then(|v| v.as_str()) {
+                    return Some(c);
+                }
+            }
+            _ => {}
+        }
+        let first = self.stack_trace()?.lines().next()?.trim();
+        let class = class_name(first);
+        (class.contains('.') && !class.contains(' ')).then_some(class)
+    }
+
+    /// The top `n` frames of the stack trace, preferring frames from the given package
+    /// prefixes and falling back to the top of the trace when none match.
+    pub fn top_frames(&self, in_app: &[String], n: usize) -> Vec<&str> {
+        let Some(trace) = self.stack_trace() else { return Vec::new() };
+        let frames: Vec<&str> = trace
+            .lines()
+            .filter_map(|l| l.trim_start().strip_prefix("at "))
+            .collect();
+        let in_app_frames: Vec<&str> = frames
+            .iter()
+            .filter(|f| in_app.iter().any(|p| f.starts_with(p.as_str())))
+            .copied()
+            .take(n)
+            .collect();
+        if in_app_frames.is_empty() {
+            frames.into_iter().take(n).collect()
+        } else {
+            in_app_frames
+        }
+    }
+
     pub fn trace_id(&self) -> Option<&str> {
         self.trace_id.as_deref().filter(|s| !s.is_empty())
     }
@@ -45,17 +255,97 @@ impl Log {
         normalize_message(&self.message)
     }
 
-    /// Group key: (container, logger, fingerprint hash). Pod intentionally excluded
-    /// so restarts / replicas merge into the same aggregate.
-    pub fn aggregation_key(&self, container: &str) -> String {
-        let normalized = self.normalized_message();
-        let mut h = DefaultHasher::new();
-        normalized.hash(&mut h);
+    /// Group key: (namespace/container, logger, fingerprint hash). Pod intentionally
+    /// excluded so restarts / replicas merge into the same aggregate.
+    ///
+    /// With a stack trace the fingerprint is the exception class plus the top frames
+    /// (line numbers dropped), so the message text doesn't matter. Without one it is the
+    /// exception class, if any, plus the normalized message.
+    pub fn aggregation_key(&self, namespace: &str, container: &str, fingerprint: &Fingerprint) -> String {
+        if let Some(lifecycle) = &self.lifecycle {
+            return format!("{namespace}/{container}|lifecycle|{}", lifecycle.reason);
+        }
+        if let Some(spike) = &self.spike {
+            return format!("{namespace}/{container}|spike|{}", spike.level);
+        }
+        let mut h = StableHasher::new();
+        if let Some(class) = self.exception_class() {
+            h.write(class);
+        }
+        let frames = if fingerprint.stack_frames > 0 {
+            self.top_frames(&fingerprint.in_app, fingerprint.stack_frames)
+        } else {
+            Vec::new()
+        };
+        if frames.is_empty() {
+            h.write(&self.normalized_message());
+        } else {
+            for frame in frames {
+                h.write(&normalize_frame(frame));
+            }
+        }
         let logger = self.logger_name.as_deref().unwrap_or("");
-        format!("{container}|{logger}|{:x}", h.finish())
+        format!("{namespace}/{container}|{logger}|{:x}", h.finish())
+    }
+}
+
+/// Redact every string in a JSON value, however deeply nested.
+fn redact_json(value: &mut serde_json::Value, redactor: &crate::redact::Redactor) {
+    match value {
+        serde_json::Value::String(s) => *s = redactor.redact(s),
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|v| redact_json(v, redactor)),
+        serde_json::Value::Object(fields) => fields.values_mut().for_each(|v| redact_json(v, redactor)),
+        _ => {}
+    }
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is the same across Rust releases, so
+/// it is what keys and hashes that end up in the state store are built with.
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    pub fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Add a string, terminated so that `("ab", "c")` and `("a", "bc")` differ.
+    pub fn write(&mut self, s: &str) {
+        for byte in s.bytes().chain([0xff]) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// How `Log::aggregation_key` fingerprints entries that carry a stack trace.
+#[derive(Debug, Clone, Default)]
+pub struct Fingerprint {
+    /// Frames hashed from the top of the trace. 0 always uses the message instead.
+    pub stack_frames: usize,
+    /// Package prefixes of our own code. Frames outside them are skipped, unless no
+    /// frame matches at all.
+    pub in_app: Vec<String>,
+}
+
+/// `no.nav.Foo.bar(Foo.kt:42)` -> `no.nav.Foo.bar(Foo.kt)`, so unrelated edits in the
+/// same file don't split an aggregate.
+fn normalize_frame(frame: &str) -> String {
+    let frame = frame.trim();
+    match frame.strip_suffix(')').and_then(|f| f.rsplit_once(':')) {
+        Some((head, line)) if !line.is_empty() && line.bytes().all(|b| b.is_ascii_digit()) => format!("{head})"),
+        _ => frame.to_string(),
     }
 }
 
+/// `java.lang.IllegalStateException: boom` -> `java.lang.IllegalStateException`.
+fn class_name(s: &str) -> &str {
+    s.split(':').next().unwrap_or(s).trim()
+}
+
 fn normalize_message(input: &str) -> String {
     use regex::Regex;
     use std::sync::OnceLock;
@@ -100,18 +390,46 @@ fn normalize_message(input: &str) -> String {
 /// A representative view of an aggregate, used to render a Slack message.
 pub struct AlertView<'a> {
     pub sample: &'a Log,
+    pub cluster: &'a str,
+    pub namespace: &'a str,
     pub container: &'a str,
     pub count: u32,
     pub first_seen: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
     pub pods: &'a HashSet<String>,
     pub trace_ids: &'a HashSet<String>,
+    /// Aggregation key, sent back by the interactive buttons.
+    pub key: &'a str,
+    /// Occurrences a threshold rule held back before the first post, 0 if none.
+    pub buffered: u32,
+    /// Who acknowledged the alert from Slack.
+    pub acked_by: Option<&'a str>,
+    /// Who muted the alert from Slack, and until when.
+    pub muted: Option<(&'a str, DateTime<Utc>)>,
+    /// Set once the aggregate has gone cold: how long it has been quiet.
+    pub resolved_after: Option<Duration>,
+    /// Who resolved the alert from Slack, if it didn't go cold by itself.
+    pub resolved_by: Option<&'a str>,
+    /// Package prefixes of our own code, used to pick stack frames worth showing.
+    pub in_app: &'a [String],
 }
 
 impl<'a> AlertView<'a> {
+    fn emoji(&self) -> &'static str {
+        if self.resolved_after.is_some() {
+            ":white_check_mark:"
+        } else {
+            ":code-on-fire:"
+        }
+    }
+
     pub fn fallback_text(&self) -> String {
+        let resolved = if self.resolved_after.is_some() { " resolved" } else { "" };
         format!(
-            ":code-on-fire: {} (x{}): {}",
+            "{}{} {}/{} (x{}): {}",
+            self.emoji(),
+            resolved,
+            self.namespace,
             self.container,
             self.count,
             truncate(&self.sample.message, 200)
@@ -119,7 +437,7 @@ impl<'a> AlertView<'a> {
     }
 
     pub fn to_blocks(&self) -> serde_json::Value {
-        let cluster = crate::env("NAIS_CLUSTER_NAME");
+        let cluster = self.cluster;
         let mut sorted_traces: Vec<&String> =
             self.trace_ids.iter().filter(|s| !s.is_empty()).collect();
         sorted_traces.sort();
@@ -132,17 +450,28 @@ impl<'a> AlertView<'a> {
         let from = self.first_seen - Duration::minutes(1);
         let to = self.last_seen + Duration::minutes(1);
 
-        let normalized_for_filter = self.sample.normalized_message();
-        let line_filter_hint = filter_hint(&self.sample.message, &normalized_for_filter);
+        // Lifecycle and spike alerts have no matching log line to search for.
+        let line_filter_hint = if self.sample.lifecycle().is_some() || self.sample.spike().is_some() {
+            String::new()
+        } else {
+            let normalized_for_filter = self.sample.normalized_message();
+            filter_hint(&self.sample.message, &normalized_for_filter)
+        };
         let grafana_log_url =
-            resolve_grafana_loki(self.container, &cluster, from, to, &line_filter_hint);
-        let peisen_url = resolve_peisen_url(&cluster, &single_trace, from, to);
-        let team_logs_url =
-            resolve_team_logs_url(self.container, &cluster, from, to, &line_filter_hint);
+            resolve_grafana_loki(self.container, cluster, from, to, &line_filter_hint);
+        let peisen_url = resolve_peisen_url(cluster, &single_trace, from, to);
+        let team_logs_url = resolve_team_logs_url(
+            self.namespace,
+            self.container,
+            cluster,
+            from,
+            to,
+            &line_filter_hint,
+        );
 
         let mut action_elements: Vec<serde_json::Value> = Vec::new();
         if !single_trace.is_empty() {
-            let grafana_trace_url = resolve_grafana_url(&cluster, &single_trace);
+            let grafana_trace_url = resolve_grafana_url(cluster, &single_trace);
             action_elements.push(json!({
                 "type": "button",
                 "text": { "type": "plain_text", "text": "trace :grafana:", "emoji": true },
@@ -168,8 +497,17 @@ impl<'a> AlertView<'a> {
             "url": peisen_url,
             "action_id": "button-action-4"
         }));
+        // Handled by the interaction endpoint, see `interact`.
+        if self.resolved_after.is_none() {
+            if self.acked_by.is_none() {
+                action_elements.push(self.action_button("acknowledge", "ack :eyes:"));
+            }
+            action_elements.push(self.action_button("mute_1h", "mute 1h :mute:"));
+            action_elements.push(self.action_button("mute_24h", "mute 24h :mute:"));
+            action_elements.push(self.action_button("resolve", "resolve :white_check_mark:"));
+        }
 
-        let cluster_label = match cluster.as_str() {
+        let cluster_label = match cluster {
             "prod-gcp" => ":alert: PROD :alert:",
             _ => "DEV",
         };
@@ -177,82 +515,206 @@ impl<'a> AlertView<'a> {
         let pods_line = format_pods(self.pods);
         let traces_line = format_traces(self.trace_ids);
         let header_text = if self.count > 1 {
-            format!(":code-on-fire: {} (x{})", self.container, self.count)
+            format!("{} {}/{} (x{})", self.emoji(), self.namespace, self.container, self.count)
         } else {
-            format!(":code-on-fire: {}", self.container)
+            format!("{} {}/{}", self.emoji(), self.namespace, self.container)
         };
 
-        let stats_text = format!(
+        let mut stats_text = format!(
             "count: {}   first: {}   last: {}",
             self.count,
             self.first_seen.format("%Y-%m-%d %H:%M:%S UTC"),
             self.last_seen.format("%Y-%m-%d %H:%M:%S UTC")
         );
+        if self.buffered > 0 {
+            stats_text.push_str(&format!("\nposted after {} occurrences (threshold rule)", self.buffered));
+        }
+        if let Some(user) = self.acked_by {
+            stats_text.push_str(&format!("\nacknowledged by {user}"));
+        }
+        if let Some((user, until)) = self.muted {
+            stats_text.push_str(&format!("\nmuted by {} until {}", user, until.format("%Y-%m-%d %H:%M UTC")));
+        }
+        if let Some(quiet) = self.resolved_after {
+            stats_text.push_str(&format!(
+                "\nresolved after {}, quiet for {}",
+                format_duration(self.last_seen - self.first_seen),
+                format_duration(quiet)
+            ));
+            if let Some(user) = self.resolved_by {
+                stats_text.push_str(&format!(" (resolved by {user})"));
+            }
+        }
 
-        json!({
-            "blocks": [
-                {
-                    "type": "header",
-                    "text": {
-                        "type": "plain_text",
-                        "text": header_text,
-                        "emoji": true
-                    }
-                },
-                {
-                    "type": "rich_text",
-                    "elements": [
-                        {
-                            "type": "rich_text_section",
-                            "elements": [
-                                { "type": "text", "text": pods_line, "style": { "italic": true } }
-                            ]
-                        }
-                    ]
-                },
-                {
-                    "type": "section",
-                    "text": { "type": "plain_text", "text": cluster_label, "emoji": true }
-                },
-                { "type": "divider" },
-                {
-                    "type": "section",
-                    "text": { "type": "plain_text", "text": stats_text, "emoji": true }
-                },
-                {
-                    "type": "section",
-                    "text": {
-                        "type": "plain_text",
-                        "text": format!("logger: {}", self.sample.logger_name().unwrap_or("log")),
-                        "emoji": true
+        let mut logger_line = format!("logger: {}", self.sample.logger_name().unwrap_or("log"));
+        if let Some(thread) = self.sample.thread_name() {
+            logger_line.push_str(&format!("   thread: {thread}"));
+        }
+
+        let mut blocks = vec![
+            json!({
+                "type": "header",
+                "text": { "type": "plain_text", "text": header_text, "emoji": true }
+            }),
+            json!({
+                "type": "rich_text",
+                "elements": [
+                    {
+                        "type": "rich_text_section",
+                        "elements": [
+                            { "type": "text", "text": pods_line, "style": { "italic": true } }
+                        ]
                     }
-                },
-                {
-                    "type": "rich_text",
-                    "elements": [
-                        {
-                            "type": "rich_text_preformatted",
-                            "elements": [
-                                { "type": "text", "text": self.sample.message.clone() }
-                            ]
-                        }
-                    ]
-                },
-                { "type": "divider" },
-                {
-                    "type": "section",
-                    "text": { "type": "plain_text", "text": traces_line, "emoji": true }
-                },
-                {
-                    "type": "actions",
-                    "elements": action_elements
-                }
-            ]
-        })["blocks"]
-            .clone()
+                ]
+            }),
+            json!({
+                "type": "section",
+                "text": { "type": "plain_text", "text": cluster_label, "emoji": true }
+            }),
+            json!({ "type": "divider" }),
+            json!({
+                "type": "section",
+                "text": { "type": "plain_text", "text": stats_text, "emoji": true }
+            }),
+            json!({
+                "type": "section",
+                "text": { "type": "plain_text", "text": logger_line, "emoji": true }
+            }),
+            preformatted(&self.sample.message),
+        ];
+
+        if let Some(spike) = self.sample.spike() {
+            let per = format_duration(Duration::seconds(spike.bucket_seconds as i64));
+            blocks.push(preformatted(&format!(
+                "{} per {}: {} ({:.1}x the baseline of {:.1})\n{}",
+                spike.level,
+                per,
+                spike.count,
+                spike.count as f64 / spike.baseline.max(1.0),
+                spike.baseline,
+                sparkline(&spike.history)
+            )));
+        }
+
+        // Exception details go right below the message.
+        if let Some(class) = self.sample.exception_class() {
+            let mut text = class.to_string();
+            for frame in self.sample.top_frames(self.in_app, 5) {
+                text.push_str(&format!("\n    at {frame}"));
+            }
+            blocks.push(preformatted(&text));
+        }
+
+        blocks.push(json!({ "type": "divider" }));
+        blocks.push(json!({
+            "type": "section",
+            "text": { "type": "plain_text", "text": traces_line, "emoji": true }
+        }));
+        blocks.push(json!({ "type": "actions", "elements": action_elements }));
+        serde_json::Value::Array(blocks)
+    }
+
+    fn action_button(&self, action_id: &str, text: &str) -> serde_json::Value {
+        json!({
+            "type": "button",
+            "text": { "type": "plain_text", "text": text, "emoji": true },
+            "value": self.key,
+            "action_id": action_id
+        })
+    }
+}
+
+/// A full stack trace, posted as thread replies under the alert.
+pub struct StackTraceView<'a> {
+    pub trace: &'a str,
+}
+
+impl<'a> StackTraceView<'a> {
+    /// Slack caps a text element at 3000 characters, so long traces are split on line
+    /// boundaries and cut off after a few chunks.
+    const CHUNK: usize = 2900;
+    const MAX_CHUNKS: usize = 5;
+
+    pub fn fallback_text(&self) -> String {
+        format!("stack trace: {}", truncate(self.trace.lines().next().unwrap_or(""), 200))
+    }
+
+    pub fn to_blocks(&self) -> serde_json::Value {
+        let mut chunks: Vec<String> = vec![String::new()];
+        for line in self.trace.lines() {
+            let current = chunks.last_mut().expect("never empty");
+            if !current.is_empty() && current.len() + line.len() + 1 > Self::CHUNK {
+                chunks.push(String::new());
+            }
+            let current = chunks.last_mut().expect("never empty");
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(&truncate(line, Self::CHUNK));
+        }
+        let total = chunks.len();
+        chunks.truncate(Self::MAX_CHUNKS);
+        if total > Self::MAX_CHUNKS
+            && let Some(last) = chunks.last_mut()
+        {
+            last.push_str(&format!("\n… ({} more chunks cut)", total - Self::MAX_CHUNKS));
+        }
+
+        serde_json::Value::Array(chunks.iter().map(|chunk| preformatted(chunk)).collect())
+    }
+}
+
+/// A distinct message grouped into an aggregate, shown as a thread reply under the alert.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub message: String,
+    pub pod: String,
+    pub trace_id: Option<String>,
+    pub seen: DateTime<Utc>,
+}
+
+/// A batch of variants rendered as one thread reply.
+pub struct ThreadView<'a> {
+    pub variants: &'a [Variant],
+}
+
+impl<'a> ThreadView<'a> {
+    pub fn fallback_text(&self) -> String {
+        format!("{} more variant(s) of this error", self.variants.len())
+    }
+
+    pub fn to_blocks(&self) -> serde_json::Value {
+        let mut blocks = Vec::new();
+        for variant in self.variants {
+            let context = format!(
+                "{}   {}   trace_id: {}",
+                variant.seen.format("%Y-%m-%d %H:%M:%S UTC"),
+                variant.pod,
+                variant.trace_id.as_deref().unwrap_or("-")
+            );
+            blocks.push(json!({
+                "type": "context",
+                "elements": [ { "type": "plain_text", "text": context, "emoji": true } ]
+            }));
+            blocks.push(preformatted(&truncate(&variant.message, 2900)));
+        }
+        serde_json::Value::Array(blocks)
     }
 }
 
+/// A block of monospaced text.
+fn preformatted(text: &str) -> serde_json::Value {
+    json!({
+        "type": "rich_text",
+        "elements": [
+            {
+                "type": "rich_text_preformatted",
+                "elements": [ { "type": "text", "text": text } ]
+            }
+        ]
+    })
+}
+
 fn format_pods(pods: &HashSet<String>) -> String {
     let mut sorted: Vec<&String> = pods.iter().collect();
     sorted.sort();
@@ -285,11 +747,30 @@ fn format_traces(trace_ids: &HashSet<String>) -> String {
     }
 }
 
-fn truncate(s: &str, max: usize) -> String {
+/// One bar per value, scaled to the largest.
+pub fn sparkline(values: &[u64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().max().unwrap_or(0).max(1);
+    values
+        .iter()
+        .map(|v| BARS[((v * (BARS.len() as u64 - 1)) / max) as usize])
+        .collect()
+}
+
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.num_seconds().max(0);
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+pub fn truncate(s: &str, max: usize) -> String {
     if s.len() <= max {
         s.to_string()
     } else {
-        format!("{}…", &s[..max])
+        format!("{}…", &s[..s.floor_char_boundary(max)])
     }
 }
 
@@ -319,6 +800,7 @@ fn filter_hint(original: &str, normalized: &str) -> String {
 }
 
 fn resolve_team_logs_url(
+    namespace: &str,
     container: &str,
     cluster: &str,
     from: DateTime<Utc>,
@@ -332,7 +814,6 @@ fn resolve_team_logs_url(
     let end = to.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
     let time_range = format!("{start}%2F{end}");
 
-    let namespace = crate::env("NAIS_NAMESPACE");
     let severity = "ERROR";
 
     let mut query_lines = vec![
@@ -431,17 +912,148 @@ mod tests {
 
     #[test]
     fn aggregation_key_stable_across_volatile_tokens() {
-        let make = |m: &str| Log {
-            level: "ERROR".into(),
-            timestamp: None,
-            logger_name: Some("foo".into()),
-            message: m.to_string(),
-            trace_id: None,
-            span_id: None,
-            hostname: None,
+        let make = |m: &str| -> Log {
+            serde_json::from_value(json!({ "level": "ERROR", "logger_name": "foo", "message": m }))
+                .unwrap()
         };
+        let fp = Fingerprint::default();
         let a = make("NPE in handleEvent(eventId=12345678-1234-1234-1234-123456789012)");
         let b = make("NPE in handleEvent(eventId=87654321-4321-4321-4321-210987654321)");
-        assert_eq!(a.aggregation_key("c1"), b.aggregation_key("c1"));
+        assert_eq!(a.aggregation_key("ns", "c1", &fp), b.aggregation_key("ns", "c1", &fp));
+        assert_ne!(a.aggregation_key("ns", "c1", &fp), a.aggregation_key("other", "c1", &fp));
+    }
+
+    #[test]
+    fn aggregation_key_does_not_depend_on_the_toolchain() {
+        let log: Log =
+            serde_json::from_value(json!({ "level": "ERROR", "logger_name": "foo", "message": "boom 42" })).unwrap();
+        // Persisted state is matched against these keys; this must never change.
+        assert_eq!(log.aggregation_key("ns", "c1", &Fingerprint::default()), "ns/c1|foo|cc3773b6313e657");
+    }
+
+    #[test]
+    fn lifecycle_key_per_container_and_reason() {
+        let make = |reason: &str, restarts: i32| {
+            Log::from_lifecycle(
+                Lifecycle {
+                    reason: reason.into(),
+                    restart_count: restarts,
+                    exit_code: Some(137),
+                    termination_reason: Some("OOMKilled".into()),
+                    termination_message: None,
+                },
+                None,
+            )
+        };
+        assert_eq!(
+            make("OOMKilled", 1).aggregation_key("ns", "c1", &Fingerprint::default()),
+            make("OOMKilled", 2).aggregation_key("ns", "c1", &Fingerprint::default())
+        );
+        assert_ne!(
+            make("OOMKilled", 1).aggregation_key("ns", "c1", &Fingerprint::default()),
+            make("CrashLoopBackOff", 1).aggregation_key("ns", "c1", &Fingerprint::default())
+        );
+        assert!(make("OOMKilled", 3).message.contains("exit code: 137"));
+    }
+
+    #[test]
+    fn spike_renders_a_rate_chart() {
+        let log = Log::from_spike(Spike {
+            level: "WARN".into(),
+            bucket_seconds: 60,
+            count: 240,
+            baseline: 10.0,
+            history: vec![8, 10, 12, 240],
+            pods: vec!["c1-a".into()],
+        });
+        assert_eq!(log.aggregation_key("ns", "c1", &Fingerprint::default()), "ns/c1|spike|WARN");
+        assert_eq!(sparkline(&[8, 10, 12, 240]), "▁▁▁█");
+
+        let (pods, traces) = (HashSet::new(), HashSet::new());
+        let view = AlertView {
+            sample: &log,
+            cluster: "dev-gcp",
+            namespace: "ns",
+            container: "c1",
+            count: 1,
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            pods: &pods,
+            trace_ids: &traces,
+            key: "ns/c1|spike|WARN",
+            buffered: 0,
+            acked_by: None,
+            muted: None,
+            resolved_after: None,
+            resolved_by: None,
+            in_app: &[],
+        };
+        let blocks = view.to_blocks().to_string();
+        assert!(blocks.contains("WARN per 1m: 240 (24.0x the baseline of 10.0)"));
+    }
+
+    #[test]
+    fn exception_class_and_in_app_frames() {
+        let log: Log = serde_json::from_value(json!({
+            "level": "ERROR",
+            "message": "feilet",
+            "thread_name": "main",
+            "stack_trace": "java.lang.IllegalStateException: boom\n\tat kotlin.Foo.bar(Foo.kt:1)\n\tat no.nav.helved.Service.run(Service.kt:42)\n\tat no.nav.helved.Main.main(Main.kt:7)"
+        }))
+        .unwrap();
+        assert_eq!(log.exception_class(), Some("java.lang.IllegalStateException"));
+        assert_eq!(
+            log.top_frames(&["no.nav".to_string()], 1),
+            vec!["no.nav.helved.Service.run(Service.kt:42)"]
+        );
+        assert_eq!(log.top_frames(&["com.other".to_string()], 1), vec!["kotlin.Foo.bar(Foo.kt:1)"]);
+
+        let typed: Log = serde_json::from_value(json!({
+            "level": "ERROR", "message": "feilet", "error.type": "java.io.IOException"
+        }))
+        .unwrap();
+        assert_eq!(typed.exception_class(), Some("java.io.IOException"));
+        let fp = Fingerprint::default();
+        assert_ne!(typed.aggregation_key("ns", "c1", &fp), log.aggregation_key("ns", "c1", &fp));
+    }
+
+    #[test]
+    fn stack_fingerprint_ignores_message_and_line_numbers() {
+        let make = |message: &str, trace: &str| -> Log {
+            serde_json::from_value(json!({ "level": "ERROR", "message": message, "stack_trace": trace }))
+                .unwrap()
+        };
+        let fp = Fingerprint { stack_frames: 2, in_app: vec!["no.nav".into()] };
+        let a = make(
+            "Fant ikke sak 1",
+            "java.lang.NullPointerException\n\tat java.util.Objects.x(Objects.java:1)\n\tat no.nav.A.run$lambda$1(A.kt:10)\n\tat no.nav.B.call(B.kt:5)",
+        );
+        let b = make(
+            "Cannot invoke \"String.length()\" because value is null",
+            "java.lang.NullPointerException\n\tat java.util.Objects.x(Objects.java:2)\n\tat no.nav.A.run$lambda$1(A.kt:12)\n\tat no.nav.B.call(B.kt:9)",
+        );
+        let c = make(
+            "Fant ikke sak 1",
+            "java.lang.NullPointerException\n\tat no.nav.C.other(C.kt:1)",
+        );
+        assert_eq!(a.aggregation_key("ns", "c1", &fp), b.aggregation_key("ns", "c1", &fp));
+        assert_ne!(a.aggregation_key("ns", "c1", &fp), c.aggregation_key("ns", "c1", &fp));
+        let by_message = Fingerprint::default();
+        assert_ne!(a.aggregation_key("ns", "c1", &by_message), b.aggregation_key("ns", "c1", &by_message));
+    }
+
+    #[test]
+    fn frames_only_lose_their_line_number() {
+        assert_eq!(normalize_frame("no.nav.A.run$lambda$1(A.kt:10)"), "no.nav.A.run$lambda$1(A.kt)");
+        assert_eq!(normalize_frame("java.lang.Thread.run(Unknown Source)"), "java.lang.Thread.run(Unknown Source)");
+        assert_ne!(normalize_frame("no.nav.A.handleV2(A.kt:1)"), normalize_frame("no.nav.A.handle(A.kt:1)"));
+        assert_ne!(normalize_frame("no.nav.A.step1(A.kt:1)"), normalize_frame("no.nav.A.step2(A.kt:1)"));
+    }
+
+    #[test]
+    fn durations_are_human_readable() {
+        assert_eq!(format_duration(Duration::seconds(42)), "42s");
+        assert_eq!(format_duration(Duration::minutes(10)), "10m");
+        assert_eq!(format_duration(Duration::minutes(75)), "1h 15m");
     }
 }
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use tokio::time::{Duration, Instant};
+
+use crate::parser::annotation;
+
+/// Pod annotation with a regex for lines that start a new event. Lines that do not
+/// match it are joined to the event before. `helved-logs/multiline-start.<container>`
+/// overrides it for a single container.
+pub const START_ANNOTATION: &str = "helved-logs/multiline-start";
+/// Pod annotation overriding the continuation regex.
+pub const CONTINUATION_ANNOTATION: &str = "helved-logs/multiline-continuation";
+
+/// Indented lines (`\tat ...`), `Caused by:`, `Suppressed:`, `... 12 more`, and the
+/// unindented exception header (`java.lang.IllegalStateException: boom`) that follows the
+/// log line itself.
+const DEFAULT_CONTINUATION: &str =
+    r"^(?:\s|Caused by:|Suppressed:|\.\.\. \d+ (?:more|common frames omitted)|[\w$.]+(?:Exception|Error|Throwable)(?::|$))";
+
+#[derive(Debug, Clone)]
+pub struct MultilineConfig {
+    pub start: Option<Regex>,
+    pub continuation: Regex,
+    /// How long an event waits for more lines before it is emitted.
+    pub flush_timeout: Duration,
+    /// Lines past this size are dropped until the next event starts.
+    pub max_bytes: usize,
+}
+
+impl Default for MultilineConfig {
+    fn default() -> Self {
+        MultilineConfig {
+            start: None,
+            continuation: Regex::new(DEFAULT_CONTINUATION).expect("default continuation pattern"),
+            flush_timeout: Duration::from_millis(1000),
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl MultilineConfig {
+    /// `MULTILINE_START`, `MULTILINE_CONTINUATION`, `MULTILINE_FLUSH_MS` and
+    /// `MULTILINE_MAX_BYTES`.
+    pub fn from_env() -> Result<Self> {
+        let var = |key| std::env::var(key).ok().filter(|v| !v.trim().is_empty());
+        let mut config = MultilineConfig::default();
+        if let Some(start) = var("MULTILINE_START") {
+            config.start = Some(Regex::new(&start).context("invalid MULTILINE_START")?);
+        }
+        if let Some(continuation) = var("MULTILINE_CONTINUATION") {
+            config.continuation = Regex::new(&continuation).context("invalid MULTILINE_CONTINUATION")?;
+        }
+        if let Some(ms) = var("MULTILINE_FLUSH_MS") {
+            config.flush_timeout = Duration::from_millis(ms.parse().context("invalid MULTILINE_FLUSH_MS")?);
+        }
+        if let Some(bytes) = var("MULTILINE_MAX_BYTES") {
+            config.max_bytes = bytes.parse().context("invalid MULTILINE_MAX_BYTES")?;
+        }
+        Ok(config)
+    }
+
+    /// Rules for one container, honouring the pod's annotations.
+    pub fn for_container(
+        &self,
+        annotations: &BTreeMap<String, String>,
+        container: &str,
+    ) -> Result<MultilineConfig> {
+        let mut config = self.clone();
+        if let Some(start) = annotation(annotations, START_ANNOTATION, container) {
+            config.start = Some(Regex::new(start).context(format!("invalid {START_ANNOTATION}"))?);
+        }
+        if let Some(continuation) = annotation(annotations, CONTINUATION_ANNOTATION, container) {
+            config.continuation =
+                Regex::new(continuation).context(format!("invalid {CONTINUATION_ANNOTATION}"))?;
+        }
+        Ok(config)
+    }
+
+    fn is_continuation(&self, line: &str) -> bool {
+        self.continuation.is_match(line) || self.start.as_ref().is_some_and(|s| !s.is_match(line))
+    }
+}
+
+/// Joins the physical lines of one log stream into events, so a plain text stack trace
+/// reaches the parser as one entry instead of one per frame.
+pub struct Assembler {
+    config: MultilineConfig,
+    pending: String,
+    truncated: bool,
+    deadline: Option<Instant>,
+}
+
+impl Assembler {
+    pub fn new(config: MultilineConfig) -> Self {
+        Assembler { config, pending: String::new(), truncated: false, deadline: None }
+    }
+
+    /// Add a line. Returns the previous event once this line starts a new one.
+    pub fn push(&mut self, line: &str) -> Option<String> {
+        if !self.pending.is_empty() && self.config.is_continuation(line) {
+            if self.pending.len() + 1 + line.len() <= self.config.max_bytes {
+                self.pending.push('\n');
+                self.pending.push_str(line);
+            } else if !self.truncated {
+                self.pending.push_str("\n...");
+                self.truncated = true;
+            }
+            self.deadline = Some(Instant::now() + self.config.flush_timeout);
+            return None;
+        }
+        let finished = self.flush();
+        self.pending.push_str(line);
+        self.deadline = Some(Instant::now() + self.config.flush_timeout);
+        finished
+    }
+
+    /// When the pending event should be emitted if no more lines arrive.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Emit the pending event, if any.
+    pub fn flush(&mut self) -> Option<String> {
+        self.deadline = None;
+        self.truncated = false;
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(std::mem::take(&mut self.pending))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_java_stack_trace_into_preceding_event() {
+        let mut assembler = Assembler::new(MultilineConfig::default());
+        let lines = [
+            "2025-05-19 08:00:00 ERROR Failed to sync",
+            "java.lang.IllegalStateException: boom",
+            "\tat no.nav.helved.Sync.run(Sync.kt:42)",
+            "Caused by: java.io.IOException: closed",
+            "\t... 3 more",
+            "2025-05-19 08:00:01 INFO next",
+        ];
+        let events: Vec<String> = lines.iter().filter_map(|l| assembler.push(l)).collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].lines().count(), 5);
+        assert!(events[0].starts_with("2025-05-19 08:00:00 ERROR Failed to sync\njava.lang.IllegalStateException: boom\n\tat"));
+        assert_eq!(assembler.flush().as_deref(), Some("2025-05-19 08:00:01 INFO next"));
+
+        // A start pattern joins everything else as well.
+        let config = MultilineConfig {
+            start: Some(Regex::new(r"^\d{4}-\d{2}-\d{2} ").unwrap()),
+            ..MultilineConfig::default()
+        };
+        let mut assembler = Assembler::new(config);
+        let events: Vec<String> = lines.iter().filter_map(|l| assembler.push(l)).collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].lines().count(), 5);
+        assert!(events[0].ends_with("\t... 3 more"));
+        assert_eq!(assembler.flush().as_deref(), Some("2025-05-19 08:00:01 INFO next"));
+        assert!(assembler.flush().is_none());
+    }
+
+    #[test]
+    fn logback_plain_text_trace_is_one_event_with_the_defaults() {
+        let mut assembler = Assembler::new(MultilineConfig::default());
+        let trace = "\
+2025-05-19 08:00:00.123 [kafka-consumer-1] ERROR no.nav.helved.Consumer - Failed to handle record
+no.nav.helved.UtbetalingException: Fant ikke sak
+\tat no.nav.helved.Consumer.handle(Consumer.kt:42)
+\tat org.apache.kafka.clients.consumer.KafkaConsumer.poll(KafkaConsumer.java:1234)
+Caused by: java.lang.OutOfMemoryError: Java heap space
+\tat java.base/java.util.Arrays.copyOf(Arrays.java:3537)
+\t... 12 common frames omitted
+2025-05-19 08:00:01.000 [main] INFO no.nav.helved.App - still running
+kotlin.KotlinNullPointerException
+\tat no.nav.helved.App.main(App.kt:7)";
+        let mut events: Vec<String> = trace.lines().filter_map(|l| assembler.push(l)).collect();
+        events.extend(assembler.flush());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].lines().count(), 7);
+        assert!(events[1].starts_with("2025-05-19 08:00:01.000 [main] INFO"));
+        assert!(events[1].ends_with("App.kt:7)"));
+    }
+
+    #[test]
+    fn stops_growing_at_max_bytes() {
+        let config = MultilineConfig { max_bytes: 20, ..MultilineConfig::default() };
+        let mut assembler = Assembler::new(config);
+        for line in ["ERROR boom", "\tat a.b(C.kt:1)", "\tat d.e(F.kt:2)", "\tat g.h(I.kt:3)"] {
+            assert!(assembler.push(line).is_none());
+        }
+        assert_eq!(assembler.flush().as_deref(), Some("ERROR boom\n..."));
+    }
+}
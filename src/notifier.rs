@@ -0,0 +1,230 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Handle to a message created by a `Notifier`, used to update or reply to it later.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PostedMessage {
+    pub channel: String,
+    pub ts: String,
+}
+
+impl PostedMessage {
+    /// Link to the message in Slack, e.g. `https://nav-it.slack.com/archives/C123/p1716105600000100`.
+    pub fn permalink(&self, workspace_url: &str) -> String {
+        format!(
+            "{}/archives/{}/p{}",
+            workspace_url.trim_end_matches('/'),
+            self.channel,
+            self.ts.replace('.', "")
+        )
+    }
+}
+
+/// Somewhere alerts are delivered. `blocks` is Slack Block Kit JSON and `fallback_text`
+/// the plain text version; other implementations pick whichever suits them.
+pub trait Notifier: Send + Sync + 'static {
+    fn create(
+        &self,
+        channel: &str,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> impl Future<Output = Result<PostedMessage>> + Send;
+
+    fn update(
+        &self,
+        message: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Threaded follow-up under an earlier message.
+    fn reply(
+        &self,
+        parent: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> impl Future<Output = Result<PostedMessage>> + Send;
+
+    /// Final update once the alert has gone quiet.
+    fn resolve(
+        &self,
+        message: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Whether alerts can be delivered at all, e.g. that the credentials still work.
+    fn check(&self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Writes every notification to stdout as a JSON line. Handy for running locally
+/// without a Slack token (`NOTIFIER=stdout`).
+#[derive(Default)]
+pub struct Stdout {
+    next_ts: AtomicU64,
+}
+
+impl Stdout {
+    fn print(&self, op: &str, message: &PostedMessage, blocks: serde_json::Value, text: &str) {
+        let line = serde_json::json!({
+            "op": op,
+            "channel": message.channel,
+            "ts": message.ts,
+            "text": text,
+            "blocks": blocks,
+        });
+        println!("{line}");
+    }
+
+    fn next(&self, channel: &str) -> PostedMessage {
+        PostedMessage {
+            channel: channel.to_string(),
+            ts: self.next_ts.fetch_add(1, Ordering::Relaxed).to_string(),
+        }
+    }
+}
+
+impl Notifier for Stdout {
+    async fn create(
+        &self,
+        channel: &str,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<PostedMessage> {
+        let message = self.next(channel);
+        self.print("create", &message, blocks, fallback_text);
+        Ok(message)
+    }
+
+    async fn update(
+        &self,
+        message: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<()> {
+        self.print("update", message, blocks, fallback_text);
+        Ok(())
+    }
+
+    async fn reply(
+        &self,
+        parent: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<PostedMessage> {
+        let message = self.next(&parent.channel);
+        self.print("reply", parent, blocks, fallback_text);
+        Ok(message)
+    }
+
+    async fn resolve(
+        &self,
+        message: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<()> {
+        self.print("resolve", message, blocks, fallback_text);
+        Ok(())
+    }
+}
+
+/// Keeps every notification in memory so tests can assert on what would have been sent.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Recording {
+    pub sent: std::sync::Mutex<Vec<Recorded>>,
+    /// Channels where `create` fails.
+    pub failing: std::sync::Mutex<Vec<String>>,
+    next_ts: AtomicU64,
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recorded {
+    Create { message: PostedMessage, text: String, blocks: String },
+    Update { message: PostedMessage, text: String, blocks: String },
+    Reply { parent: PostedMessage, text: String },
+    Resolve { message: PostedMessage, text: String, blocks: String },
+}
+
+#[cfg(test)]
+impl Recording {
+    pub fn sent(&self) -> Vec<Recorded> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    fn record(&self, recorded: Recorded) {
+        self.sent.lock().unwrap().push(recorded);
+    }
+
+    fn next(&self, channel: &str) -> PostedMessage {
+        PostedMessage {
+            channel: channel.to_string(),
+            ts: self.next_ts.fetch_add(1, Ordering::Relaxed).to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Notifier for Recording {
+    async fn create(
+        &self,
+        channel: &str,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<PostedMessage> {
+        if self.failing.lock().unwrap().iter().any(|c| c == channel) {
+            anyhow::bail!("channel_not_found");
+        }
+        let message = self.next(channel);
+        self.record(Recorded::Create {
+            message: message.clone(),
+            text: fallback_text.into(),
+            blocks: blocks.to_string(),
+        });
+        Ok(message)
+    }
+
+    async fn update(
+        &self,
+        message: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<()> {
+        self.record(Recorded::Update {
+            message: message.clone(),
+            text: fallback_text.into(),
+            blocks: blocks.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn reply(
+        &self,
+        parent: &PostedMessage,
+        _blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<PostedMessage> {
+        self.record(Recorded::Reply { parent: parent.clone(), text: fallback_text.into() });
+        Ok(self.next(&parent.channel))
+    }
+
+    async fn resolve(
+        &self,
+        message: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<()> {
+        self.record(Recorded::Resolve {
+            message: message.clone(),
+            text: fallback_text.into(),
+            blocks: blocks.to_string(),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,534 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::model::Log;
+
+/// Pod annotation selecting the formats tried, in order, e.g. `logfmt` or `json,text`.
+/// `helved-logs/format.<container>` overrides it for a single container.
+pub const FORMAT_ANNOTATION: &str = "helved-logs/format";
+/// Pod annotation overriding the regex used by the `text` format.
+/// `helved-logs/pattern.<container>` overrides it for a single container.
+pub const PATTERN_ANNOTATION: &str = "helved-logs/pattern";
+/// Pod annotation with field overrides, same shape as `LOG_FIELDS`.
+/// `helved-logs/fields.<container>` overrides it for a single container.
+pub const FIELDS_ANNOTATION: &str = "helved-logs/fields";
+/// Pod annotation with a comma separated list of alertable levels.
+pub const LEVELS_ANNOTATION: &str = "helved-logs/alert-levels";
+
+/// The level has to lead the line, after an optional prefix of timestamp parts and bracketed
+/// fields like `[main]`, so "retrying after error" in a message is not taken for one.
+const DEFAULT_TEXT_PATTERN: &str = r"(?i)^\s*(?:\d+[-/:.][\d/:.,TZ+-]*\s+|\[[^\]]*\]\s*)*\[?(?P<level>TRACE|DEBUG|INFO|WARN(?:ING)?|ERROR|FATAL)\b[\s:\]|-]*(?P<message>.*)";
+
+/// Where a structured line keeps the fields `Log` needs. Each field is a list of paths
+/// tried in order; a path is either a literal key (`dd.trace_id`) or a dotted path into
+/// nested objects (`log.level`).
+#[derive(Debug, Clone)]
+pub struct Fields {
+    pub level: Vec<String>,
+    pub message: Vec<String>,
+    pub timestamp: Vec<String>,
+    pub logger: Vec<String>,
+    pub trace_id: Vec<String>,
+    pub host: Vec<String>,
+}
+
+/// A partial `Fields`, from `LOG_FIELDS` or an annotation. Each field takes a path or a
+/// list of paths and replaces the whole list it overrides.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FieldOverrides {
+    level: Option<Paths>,
+    message: Option<Paths>,
+    timestamp: Option<Paths>,
+    logger: Option<Paths>,
+    trace_id: Option<Paths>,
+    host: Option<Paths>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Paths {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Paths> for Vec<String> {
+    fn from(paths: Paths) -> Self {
+        match paths {
+            Paths::One(path) => vec![path],
+            Paths::Many(paths) => paths,
+        }
+    }
+}
+
+fn paths(paths: &[&str]) -> Vec<String> {
+    paths.iter().map(|p| p.to_string()).collect()
+}
+
+impl Default for Fields {
+    /// logstash-logback-encoder first, then the names used by ECS, Go (slog, zap, logrus),
+    /// Python and Datadog.
+    fn default() -> Self {
+        Fields {
+            level: paths(&["level", "severity", "lvl", "log.level", "levelname"]),
+            message: paths(&["message", "msg"]),
+            timestamp: paths(&["@timestamp", "time", "timestamp", "ts", "asctime"]),
+            logger: paths(&["logger_name", "logger", "log.logger", "name"]),
+            trace_id: paths(&["trace_id", "traceId", "dd.trace_id", "trace.id"]),
+            host: paths(&["HOSTNAME", "host.hostname", "hostname"]),
+        }
+    }
+}
+
+impl Fields {
+    /// Apply the overrides in `json` on top of these fields.
+    pub fn merge(&self, json: &str) -> Result<Fields> {
+        let overrides: FieldOverrides = serde_json::from_str(json)?;
+        let pick = |over: Option<Paths>, base: &Vec<String>| {
+            over.map(Into::into).unwrap_or_else(|| base.clone())
+        };
+        Ok(Fields {
+            level: pick(overrides.level, &self.level),
+            message: pick(overrides.message, &self.message),
+            timestamp: pick(overrides.timestamp, &self.timestamp),
+            logger: pick(overrides.logger, &self.logger),
+            trace_id: pick(overrides.trace_id, &self.trace_id),
+            host: pick(overrides.host, &self.host),
+        })
+    }
+
+    /// Copy the configured fields to the names `Log` deserializes from. Levels are
+    /// upper-cased and numbers turned into strings, so `ERROR`, `error` and pino's `50`
+    /// can all be matched by `alert_levels`.
+    fn apply(&self, map: &mut Map<String, Value>) {
+        let fields = [
+            (&self.level, "level"),
+            (&self.message, "message"),
+            (&self.timestamp, "@timestamp"),
+            (&self.logger, "logger_name"),
+            (&self.trace_id, "trace_id"),
+            (&self.host, "HOSTNAME"),
+        ];
+        for (candidates, to) in fields {
+            let Some(value) = candidates.iter().find_map(|path| lookup(map, path)) else { continue };
+            let value = match (to, value) {
+                ("level", Value::String(level)) => Value::String(level.to_uppercase()),
+                ("@timestamp", Value::Number(epoch)) => match epoch.as_f64().and_then(from_epoch) {
+                    Some(ts) => Value::String(ts),
+                    None => continue,
+                },
+                (_, Value::String(s)) => Value::String(s),
+                (_, Value::Null) => continue,
+                (_, other) => Value::String(other.to_string()),
+            };
+            map.insert(to.to_string(), value);
+        }
+    }
+}
+
+/// A literal key wins over a nested path of the same spelling.
+fn lookup(map: &Map<String, Value>, path: &str) -> Option<Value> {
+    if let Some(value) = map.get(path) {
+        return Some(value.clone());
+    }
+    let (first, rest) = path.split_once('.')?;
+    let mut value = map.get(first)?;
+    for key in rest.split('.') {
+        value = value.as_object()?.get(key)?;
+    }
+    Some(value.clone())
+}
+
+/// zap writes seconds as a float, pino milliseconds.
+fn from_epoch(epoch: f64) -> Option<String> {
+    let millis = if epoch > 1e11 { epoch } else { epoch * 1000.0 };
+    chrono::DateTime::from_timestamp_millis(millis as i64).map(|ts| ts.to_rfc3339())
+}
+
+#[derive(Debug, Clone)]
+enum Format {
+    Json,
+    Logfmt,
+    Text(Regex),
+}
+
+/// The chain of formats used for one container. The first format that recognizes a
+/// line wins.
+#[derive(Debug, Clone)]
+pub struct Parser {
+    chain: Vec<Format>,
+    fields: Fields,
+    alert_levels: Vec<String>,
+}
+
+impl Parser {
+    /// Whether the level is one we alert on. Case-insensitive.
+    pub fn alerts_on(&self, log: &Log) -> bool {
+        self.alert_levels.iter().any(|l| l.eq_ignore_ascii_case(log.level()))
+    }
+
+    /// `Ok(None)` if no format recognized the line, `Err` if one recognized it but it was
+    /// malformed and nothing later in the chain accepted it.
+    ///
+    /// `event` may span several lines, as joined by the multiline assembler. Only the first
+    /// is parsed; the rest becomes the stack trace unless the entry already carries one.
+    pub fn parse(&self, event: &str) -> Result<Option<Log>> {
+        let (line, rest) = match event.split_once('\n') {
+            Some((line, rest)) => (line, Some(rest)),
+            None => (event, None),
+        };
+        let mut error = None;
+        for format in &self.chain {
+            let parsed = format.parse(line, &self.fields).and_then(|map| {
+                map.map(|mut map| {
+                    if let Some(rest) = rest {
+                        map.entry("stack_trace").or_insert_with(|| Value::String(rest.to_string()));
+                    }
+                    serde_json::from_value(Value::Object(map)).context(format.parse_error())
+                })
+                .transpose()
+            });
+            match parsed {
+                Ok(Some(log)) => return Ok(Some(log)),
+                Ok(None) => {}
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Format {
+    fn parse_error(&self) -> &'static str {
+        match self {
+            Format::Json => "JSON parse error",
+            Format::Logfmt => "logfmt parse error",
+            Format::Text(_) => "text parse error",
+        }
+    }
+
+    /// The line as a map of the fields `Log` deserializes from.
+    fn parse(&self, line: &str, fields: &Fields) -> Result<Option<Map<String, Value>>> {
+        match self {
+            Format::Json => {
+                let Some(json_start_idx) = line.find('{') else { return Ok(None) };
+                let mut map: Map<String, Value> = serde_json::from_str(&line[json_start_idx..])
+                    .context(self.parse_error())?;
+                fields.apply(&mut map);
+                Ok(Some(map))
+            }
+            Format::Logfmt => {
+                let mut map = parse_logfmt(line);
+                fields.apply(&mut map);
+                if !map.contains_key("level") || !map.contains_key("message") {
+                    return Ok(None);
+                }
+                Ok(Some(map))
+            }
+            Format::Text(regex) => {
+                let Some(caps) = regex.captures(line) else { return Ok(None) };
+                let mut map = Map::new();
+                for name in ["level", "message", "timestamp", "logger"] {
+                    if let Some(m) = caps.name(name) {
+                        map.insert(name.to_string(), Value::String(m.as_str().trim().to_string()));
+                    }
+                }
+                let level = map
+                    .remove("level")
+                    .ok_or_else(|| anyhow!("text pattern matched without a level"))?;
+                // Same casing as the structured formats, for display and routing.
+                let level = level.as_str().unwrap_or_default().to_uppercase();
+                map.insert("level".into(), Value::String(level));
+                map.entry("message").or_insert_with(|| Value::String(line.trim().to_string()));
+                if let Some(ts) = map.remove("timestamp") {
+                    map.insert("@timestamp".into(), ts);
+                }
+                if let Some(logger) = map.remove("logger") {
+                    map.insert("logger_name".into(), logger);
+                }
+                Ok(Some(map))
+            }
+        }
+    }
+}
+
+/// Global defaults, overridable per pod or container through annotations.
+#[derive(Debug, Clone)]
+pub struct ParserConfig {
+    pub formats: Vec<String>,
+    pub fields: Fields,
+    pub alert_levels: Vec<String>,
+    pub text_pattern: Regex,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        ParserConfig {
+            formats: vec!["json".into()],
+            fields: Fields::default(),
+            alert_levels: vec!["ERROR".into(), "FATAL".into()],
+            text_pattern: Regex::new(DEFAULT_TEXT_PATTERN).expect("default text pattern"),
+        }
+    }
+}
+
+impl ParserConfig {
+    /// `LOG_FORMAT` (comma separated chain), `LOG_FIELDS` (JSON object of field paths),
+    /// `LOG_ALERT_LEVELS` (comma separated) and `LOG_TEXT_PATTERN` (regex with a `level`
+    /// group).
+    pub fn from_env() -> Result<Self> {
+        let var = |key| std::env::var(key).ok().filter(|v| !v.trim().is_empty());
+        let mut config = ParserConfig::default();
+        if let Some(formats) = var("LOG_FORMAT") {
+            config.formats = split_list(&formats.to_lowercase());
+        }
+        if let Some(json) = var("LOG_FIELDS") {
+            config.fields = config.fields.merge(&json).context("invalid LOG_FIELDS")?;
+        }
+        if let Some(levels) = var("LOG_ALERT_LEVELS") {
+            config.alert_levels = split_list(&levels);
+        }
+        if let Some(pattern) = var("LOG_TEXT_PATTERN") {
+            config.text_pattern = text_pattern(&pattern)?;
+        }
+        config.for_container(&BTreeMap::new(), "")?;
+        Ok(config)
+    }
+
+    /// Parser for one container, honouring the pod's annotations.
+    pub fn for_container(
+        &self,
+        annotations: &BTreeMap<String, String>,
+        container: &str,
+    ) -> Result<Parser> {
+        let annotation = |key| annotation(annotations, key, container);
+        let formats = annotation(FORMAT_ANNOTATION)
+            .map(|f| split_list(&f.to_lowercase()))
+            .unwrap_or_else(|| self.formats.clone());
+        let pattern = match annotation(PATTERN_ANNOTATION) {
+            Some(p) => text_pattern(p)?,
+            None => self.text_pattern.clone(),
+        };
+        let fields = match annotation(FIELDS_ANNOTATION) {
+            Some(json) => self.fields.merge(json).context(format!("invalid {FIELDS_ANNOTATION}"))?,
+            None => self.fields.clone(),
+        };
+        let alert_levels = annotation(LEVELS_ANNOTATION)
+            .map(|l| split_list(l))
+            .unwrap_or_else(|| self.alert_levels.clone());
+
+        let chain = formats
+            .iter()
+            .map(|name| match name.as_str() {
+                "json" => Ok(Format::Json),
+                "logfmt" => Ok(Format::Logfmt),
+                "text" => Ok(Format::Text(pattern.clone())),
+                other => Err(anyhow!("unknown log format {other}")),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if chain.is_empty() {
+            bail!("no log formats configured");
+        }
+        Ok(Parser { chain, fields, alert_levels })
+    }
+}
+
+/// `<key>.<container>` if set, else `<key>`.
+pub fn annotation<'a>(
+    annotations: &'a BTreeMap<String, String>,
+    key: &str,
+    container: &str,
+) -> Option<&'a String> {
+    annotations
+        .get(&format!("{key}.{container}"))
+        .or_else(|| annotations.get(key))
+}
+
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|f| f.trim().to_string())
+        .filter(|f| !f.is_empty())
+        .collect()
+}
+
+fn text_pattern(pattern: &str) -> Result<Regex> {
+    let regex = Regex::new(pattern).context("invalid text pattern")?;
+    if !regex.capture_names().any(|n| n == Some("level")) {
+        bail!("text pattern needs a (?P<level>..) group");
+    }
+    Ok(regex)
+}
+
+/// `key=value key2="quoted value" flag` into a map of strings. Bare keys become `true`.
+fn parse_logfmt(line: &str) -> Map<String, Value> {
+    let mut map = Map::new();
+    let mut chars = line.trim().chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let key: String =
+            std::iter::from_fn(|| chars.next_if(|c| *c != '=' && !c.is_whitespace())).collect();
+        if key.is_empty() {
+            break;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            map.insert(key, Value::String("true".into()));
+            continue;
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            value.push(match escaped {
+                                'n' => '\n',
+                                't' => '\t',
+                                other => other,
+                            });
+                        }
+                    }
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value = std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())).collect();
+        }
+        map.insert(key, Value::String(value));
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logfmt_with_quotes_and_bare_keys() {
+        let map = parse_logfmt(r#"time=2025-05-19T08:00:00Z level=error msg="failed to \"sync\"" retry"#);
+        assert_eq!(map["level"], "error");
+        assert_eq!(map["msg"], "failed to \"sync\"");
+        assert_eq!(map["retry"], "true");
+    }
+
+    #[test]
+    fn annotations_select_chain_per_container() {
+        let config = ParserConfig::default();
+        let annotations = BTreeMap::from([
+            (FORMAT_ANNOTATION.to_string(), "logfmt".to_string()),
+            (format!("{FORMAT_ANNOTATION}.proxy"), "text".to_string()),
+        ]);
+
+        let app = config.for_container(&annotations, "app").unwrap();
+        let log = app.parse(r#"level=ERROR msg="boom" time=2025-05-19T08:00:00Z"#).unwrap().unwrap();
+        assert!(app.alerts_on(&log));
+        assert_eq!(log.message(), "boom");
+        assert!(log.parsed_timestamp().is_some());
+
+        let proxy = config.for_container(&annotations, "proxy").unwrap();
+        let log = proxy.parse("2025/05/19 08:00:00 [error] upstream timed out").unwrap().unwrap();
+        assert!(proxy.alerts_on(&log));
+        assert_eq!(log.message(), "upstream timed out");
+        assert!(proxy.parse("plain line without level").unwrap().is_none());
+    }
+
+    #[test]
+    fn pattern_annotation_per_container() {
+        let config = ParserConfig { formats: vec!["text".into()], ..ParserConfig::default() };
+        let annotations = BTreeMap::from([
+            (format!("{PATTERN_ANNOTATION}.envoy"), r"^(?P<message>.*)\|(?P<level>\w+)$".to_string()),
+        ]);
+
+        let envoy = config.for_container(&annotations, "envoy").unwrap();
+        let log = envoy.parse("upstream reset|error").unwrap().unwrap();
+        assert_eq!(log.message(), "upstream reset");
+
+        let app = config.for_container(&annotations, "app").unwrap();
+        assert!(app.parse("upstream reset|error").unwrap().is_none());
+    }
+
+    #[test]
+    fn text_level_must_lead_the_line() {
+        let config = ParserConfig { formats: vec!["text".into()], ..ParserConfig::default() };
+        let parser = config.for_container(&BTreeMap::new(), "app").unwrap();
+        for (line, message) in [
+            ("ERROR boom", "boom"),
+            ("[ERROR] boom", "boom"),
+            ("2025-05-19 08:00:00,123 ERROR boom", "boom"),
+            ("08:00:00.123 [main] ERROR no.nav.Foo - boom", "no.nav.Foo - boom"),
+        ] {
+            let log = parser.parse(line).unwrap().unwrap();
+            assert_eq!(log.level(), "ERROR", "{line}");
+            assert_eq!(log.message(), message, "{line}");
+        }
+        for line in [
+            "retrying after error",
+            "0 errors, 3 warnings",
+            "GET /api/error/42 took 12ms",
+            "08:00:00 request failed with error 500",
+        ] {
+            assert!(parser.parse(line).unwrap().is_none(), "{line}");
+        }
+    }
+
+    #[test]
+    fn continuation_lines_become_the_stack_trace() {
+        let config = ParserConfig { formats: vec!["text".into()], ..ParserConfig::default() };
+        let parser = config.for_container(&BTreeMap::new(), "app").unwrap();
+        let event = "08:00:00 ERROR Sync failed\njava.lang.IllegalStateException: boom\n\tat no.nav.Sync.run(Sync.kt:42)";
+        let log = parser.parse(event).unwrap().unwrap();
+        assert_eq!(log.message(), "Sync failed");
+        assert_eq!(log.exception_class(), Some("java.lang.IllegalStateException"));
+        assert!(log.stack_trace().unwrap().ends_with("(Sync.kt:42)"));
+    }
+
+    #[test]
+    fn common_aliases_and_nested_paths() {
+        let parser = ParserConfig::default().for_container(&BTreeMap::new(), "app").unwrap();
+        let log = parser
+            .parse(r#"{"log":{"level":"error","logger":"db"},"msg":"boom","dd.trace_id":"abc","ts":1747641600.5}"#)
+            .unwrap()
+            .unwrap();
+        assert!(parser.alerts_on(&log));
+        assert_eq!(log.level(), "ERROR");
+        assert_eq!(log.message(), "boom");
+        assert_eq!(log.logger_name(), Some("db"));
+        assert_eq!(log.trace_id(), Some("abc"));
+        assert_eq!(log.parsed_timestamp().unwrap().timestamp(), 1747641600);
+
+        let log = parser.parse(r#"{"severity":"fatal","message":"down"}"#).unwrap().unwrap();
+        assert!(parser.alerts_on(&log));
+        let log = parser.parse(r#"{"lvl":"warn","msg":"slow"}"#).unwrap().unwrap();
+        assert!(!parser.alerts_on(&log));
+        assert!(parser.parse("not json").unwrap().is_none());
+        assert!(parser.parse("{ broken").is_err());
+    }
+
+    #[test]
+    fn fields_and_levels_from_annotations() {
+        let config = ParserConfig::default();
+        let annotations = BTreeMap::from([
+            (FIELDS_ANNOTATION.to_string(), r#"{"level":"status.severity","message":["text"]}"#.to_string()),
+            (format!("{LEVELS_ANNOTATION}.worker"), "50,60".to_string()),
+        ]);
+
+        let app = config.for_container(&annotations, "app").unwrap();
+        let log = app.parse(r#"{"status":{"severity":"Error"},"text":"boom"}"#).unwrap().unwrap();
+        assert!(app.alerts_on(&log));
+        assert_eq!(log.message(), "boom");
+
+        let worker = config.for_container(&annotations, "worker").unwrap();
+        let log = worker.parse(r#"{"level":"50","text":"pino error"}"#).unwrap().unwrap();
+        assert!(worker.alerts_on(&log));
+
+        let bad = BTreeMap::from([(FIELDS_ANNOTATION.to_string(), r#"{"lvl":"x"}"#.to_string())]);
+        assert!(config.for_container(&bad, "app").is_err());
+    }
+}
@@ -1,35 +1,175 @@
-use anyhow::{Result, Context};
-use std::time::Duration;
-use::tokio::{io::{AsyncReadExt, AsyncWriteExt}};
-
-pub async fn health_check_server() -> Result<()> {
-    let port = 8080;
-    let addr = format!("0.0.0.0:{}", port);
-    
-    let listener = tokio::net::TcpListener::bind(&addr).await
-        .context(format!("Failed to bind TCP listener to {}", addr))?;
-
-    log::info!("[HEALTH] Health check server listening on {}", addr);
-
-    let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nOK";
-    let response_bytes = response.as_bytes();
-
-    loop {
-        match listener.accept().await {
-            Ok((mut socket, _addr)) => {
-                tokio::spawn(async move {
-                    let mut buf = [0; 1024];
-                    let _ = socket.read(&mut buf).await;
-                    if let Err(e) = socket.write_all(response_bytes).await && e.kind() != std::io::ErrorKind::BrokenPipe {
-                        log::error!("[HEALTH ERROR] Failed to write response: {}", e);
-                    }
-                });
-            }
-            Err(e) => {
-                log::error!("[HEALTH ERROR] Failed to accept connection: {}", e);
-                tokio::time::sleep(Duration::from_millis(100)).await;
-            }
-        }
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::FutureExt;
+use tokio::sync::mpsc::WeakSender;
+
+use crate::aggregator::Aggregator;
+use crate::dashboard;
+use crate::health::Health;
+use crate::http::{self, Request, Response, Router};
+use crate::interact::{self, Verifier};
+use crate::metrics::METRICS;
+use crate::model::{Log, Source};
+use crate::notifier::Notifier;
+use crate::silence::NewSilence;
+
+/// Everything the endpoints on ports 8080 and 8081 read from.
+pub struct State<N: Notifier> {
+    pub aggregator: Arc<Aggregator<N>>,
+    pub health: Arc<Health>,
+    /// Held weakly, only to report the depth of the log channel.
+    pub queue: WeakSender<(Log, Source)>,
+    /// Unset when `SLACK_SIGNING_SECRET` is missing, which disables the interaction endpoint.
+    pub verifier: Option<Verifier>,
+}
+
+/// Serve the probes, metrics, silences, aggregates and the dashboard on port 8080, and Slack
+/// interactions on port 8081, until `shutdown` resolves.
+///
+/// Only 8081 is meant to be reachable from outside the cluster. Nothing on 8080 is
+/// authenticated, so it must never sit behind the public ingress.
+pub async fn health_check_server<N: Notifier>(
+    state: State<N>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let state = Arc::new(state);
+    let shutdown = shutdown.shared();
+    if state.verifier.is_none() {
+        log::info!("[HTTP] SLACK_SIGNING_SECRET not set, Slack interactions are disabled");
+        return http::serve("0.0.0.0:8080", router(), state, shutdown).await;
     }
+    tokio::try_join!(
+        http::serve("0.0.0.0:8080", router(), state.clone(), shutdown.clone()),
+        http::serve("0.0.0.0:8081", interaction_router(), state, shutdown),
+    )?;
+    Ok(())
 }
 
+fn router<N: Notifier>() -> Router<State<N>> {
+    Router::new()
+        .route("GET", "/", root)
+        .route("GET", "/ready", ready)
+        .route("GET", "/live", live)
+        .route("GET", "/metrics", metrics)
+        .route("GET", "/silences", list_silences)
+        .route("POST", "/silences", add_silence)
+        .route("DELETE", "/silences/{id}", expire_silence)
+        .route("GET", "/api/aggregates", list_aggregates)
+        .route("GET", "/api/aggregates/{key}", get_aggregate)
+        .route("GET", "/dashboard", dashboard)
+        .quiet(&["/", "/ready", "/live", "/metrics"])
+}
+
+/// The only routes behind the public ingress. Every request is checked against the
+/// signing secret.
+fn interaction_router<N: Notifier>() -> Router<State<N>> {
+    Router::new().route("POST", "/slack/interactions", slack_interaction)
+}
+
+/// Always OK; what the NAIS probes pointed at before `/ready` and `/live`.
+async fn root<N: Notifier>(_: Request, _: Arc<State<N>>) -> Response {
+    Response::text(200, "OK")
+}
+
+async fn ready<N: Notifier>(_: Request, state: Arc<State<N>>) -> Response {
+    probe(state.health.ready())
+}
+
+async fn live<N: Notifier>(_: Request, state: Arc<State<N>>) -> Response {
+    probe(state.health.live())
+}
+
+fn probe(result: Result<(), Vec<String>>) -> Response {
+    match result {
+        Ok(()) => Response::text(200, "OK"),
+        Err(problems) => Response::text(503, &problems.join("\n")),
+    }
+}
+
+async fn metrics<N: Notifier>(_: Request, state: Arc<State<N>>) -> Response {
+    let depth = state.queue.upgrade().map_or(0, |tx| tx.max_capacity() - tx.capacity());
+    let body = METRICS.render(state.aggregator.active().await, depth);
+    Response { status: 200, content_type: "text/plain; version=0.0.4", body: body.into_bytes() }
+}
+
+async fn list_aggregates<N: Notifier>(_: Request, state: Arc<State<N>>) -> Response {
+    Response::json(200, &state.aggregator.summaries().await)
+}
+
+/// The key has to be URL encoded, it contains slashes.
+async fn get_aggregate<N: Notifier>(request: Request, state: Arc<State<N>>) -> Response {
+    match state.aggregator.detail(request.param("key")).await {
+        Some(detail) => Response::json(200, &detail),
+        None => Response::text(404, "no such aggregate"),
+    }
+}
+
+/// Filtered by the `namespace`, `container` and `logger` query parameters, and sorted by
+/// count with `sort=count`.
+async fn dashboard<N: Notifier>(request: Request, state: Arc<State<N>>) -> Response {
+    let filter = dashboard::Filter {
+        namespace: request.query("namespace").map(String::from),
+        container: request.query("container").map(String::from),
+        logger: request.query("logger").map(String::from),
+        by_count: request.query("sort") == Some("count"),
+    };
+    let aggregator = &state.aggregator;
+    Response::html(
+        200,
+        dashboard::render(
+            &filter,
+            aggregator.summaries().await,
+            aggregator.activity().await,
+            aggregator.recently_resolved().await,
+            chrono::Utc::now(),
+        ),
+    )
+}
+
+async fn list_silences<N: Notifier>(_: Request, state: Arc<State<N>>) -> Response {
+    Response::json(200, &state.aggregator.silences().await)
+}
+
+async fn add_silence<N: Notifier>(request: Request, state: Arc<State<N>>) -> Response {
+    let new: NewSilence = match request.json() {
+        Ok(new) => new,
+        Err(rejected) => return rejected,
+    };
+    match state.aggregator.add_silence(new).await {
+        Ok(silence) => Response::json(201, &silence),
+        Err(e) => Response::text(400, &e.to_string()),
+    }
+}
+
+async fn expire_silence<N: Notifier>(request: Request, state: Arc<State<N>>) -> Response {
+    if state.aggregator.expire_silence(request.param("id")).await {
+        Response::text(200, "expired")
+    } else {
+        Response::text(404, "no such silence")
+    }
+}
+
+async fn slack_interaction<N: Notifier>(request: Request, state: Arc<State<N>>) -> Response {
+    let Some(verifier) = &state.verifier else {
+        return Response::text(404, "slack interactions are not configured");
+    };
+    let verified = verifier.verify(
+        request.header("x-slack-request-timestamp").unwrap_or_default(),
+        request.header("x-slack-signature").unwrap_or_default(),
+        &request.body,
+        chrono::Utc::now().timestamp(),
+    );
+    if let Err(e) = verified {
+        log::warn!("[HTTP] rejected slack interaction: {}", e);
+        return Response::text(401, "invalid signature");
+    }
+    let payload = match interact::parse(&request.body) {
+        Ok(payload) => payload,
+        Err(e) => return Response::text(400, &format!("{e:#}")),
+    };
+    // Slack wants an answer within 3 seconds; the message is edited afterwards.
+    tokio::spawn(interact::handle(payload, state.aggregator.clone()));
+    Response::text(200, "")
+}
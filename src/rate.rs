@@ -0,0 +1,220 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc::Sender;
+
+use crate::model::{Log, Source, Spike};
+
+#[derive(Debug, Clone)]
+pub struct RateConfig {
+    /// Levels to count, case-insensitive and with aliases like `WARNING` folded into
+    /// `WARN`. Empty disables the detector.
+    pub levels: Vec<String>,
+    pub bucket: Duration,
+    /// Completed buckets the baseline is the median of.
+    pub baseline_buckets: usize,
+    /// How many times the baseline a bucket must reach to count as a spike.
+    pub factor: f64,
+    /// Buckets below this count never spike, however quiet the baseline.
+    pub min_count: u64,
+}
+
+impl Default for RateConfig {
+    fn default() -> Self {
+        RateConfig {
+            levels: vec!["WARN".into()],
+            bucket: Duration::from_secs(60),
+            baseline_buckets: 30,
+            factor: 10.0,
+            min_count: 20,
+        }
+    }
+}
+
+/// Rolling per-container, per-level line counts.
+#[derive(Debug, Default)]
+struct Series {
+    current: u64,
+    pods: BTreeSet<String>,
+    history: VecDeque<u64>,
+}
+
+/// Counts log lines per container and level, and reports buckets where the count jumps
+/// well above the median of the buckets before it.
+#[derive(Debug, Default)]
+pub struct RateTracker {
+    config: RateConfig,
+    series: Mutex<HashMap<(String, String, String), Series>>,
+}
+
+impl RateTracker {
+    pub fn new(config: RateConfig) -> Self {
+        RateTracker { config, series: Mutex::new(HashMap::new()) }
+    }
+
+    /// Called for every parsed line, whatever its level.
+    pub fn record(&self, source: &Source, level: &str) {
+        let level = canonical_level(level);
+        if !self.config.levels.iter().any(|l| canonical_level(l) == level) {
+            return;
+        }
+        let mut series = self.series.lock().unwrap();
+        let entry = series
+            .entry((source.namespace.clone(), source.container.clone(), level))
+            .or_default();
+        entry.current += 1;
+        entry.pods.insert(source.pod.clone());
+    }
+
+    /// Close the current bucket for every series and return the ones that spiked.
+    fn rotate(&self) -> Vec<(Spike, Source)> {
+        let mut spikes = Vec::new();
+        let mut series = self.series.lock().unwrap();
+        for ((namespace, container, level), s) in series.iter_mut() {
+            let count = std::mem::take(&mut s.current);
+            let pods = std::mem::take(&mut s.pods);
+            // Wait for half a baseline of history so a fresh start doesn't alert.
+            if s.history.len() * 2 >= self.config.baseline_buckets
+                && let Some(baseline) = median(&s.history)
+                && count >= self.config.min_count
+                && count as f64 >= self.config.factor * baseline.max(1.0)
+            {
+                let mut history: Vec<u64> = s.history.iter().copied().collect();
+                history.push(count);
+                let pods: Vec<String> = pods.into_iter().collect();
+                let source = Source {
+                    namespace: namespace.clone(),
+                    pod: pods.first().cloned().unwrap_or_default(),
+                    container: container.clone(),
+                    previous: false,
+                };
+                let spike = Spike {
+                    level: level.clone(),
+                    bucket_seconds: self.config.bucket.as_secs(),
+                    count,
+                    baseline,
+                    history,
+                    pods,
+                };
+                spikes.push((spike, source));
+            }
+            s.history.push_back(count);
+            while s.history.len() > self.config.baseline_buckets {
+                s.history.pop_front();
+            }
+        }
+        spikes
+    }
+
+    /// Spawn the bucket task. Spikes go into the log channel as synthetic entries, so
+    /// they are routed, aggregated and resolved like any other alert.
+    pub fn spawn(self: Arc<Self>, tx: Sender<(Log, Source)>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            if self.config.levels.is_empty() {
+                return;
+            }
+            let mut ticker = tokio::time::interval(self.config.bucket);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                for (spike, source) in self.rotate() {
+                    log::info!(
+                        "{} logs spiked in {}: {} vs a baseline of {:.1}",
+                        spike.level,
+                        source,
+                        spike.count,
+                        spike.baseline
+                    );
+                    if tx.send((Log::from_spike(spike), source)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Upper case, with the names other loggers use mapped to ours.
+fn canonical_level(level: &str) -> String {
+    let level = level.to_uppercase();
+    match level.as_str() {
+        "WARNING" => "WARN".into(),
+        "ERR" => "ERROR".into(),
+        "CRITICAL" | "CRIT" | "PANIC" => "FATAL".into(),
+        _ => level,
+    }
+}
+
+fn median(values: &VecDeque<u64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted: Vec<u64> = values.iter().copied().collect();
+    sorted.sort_unstable();
+    let mid = sorted.len() / 2;
+    Some(if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) as f64 / 2.0
+    } else {
+        sorted[mid] as f64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(pod: &str) -> Source {
+        Source { namespace: "helved".into(), pod: pod.into(), container: "app".into(), previous: false }
+    }
+
+    fn bucket(tracker: &RateTracker, warns: u64) -> Vec<(Spike, Source)> {
+        for i in 0..warns {
+            tracker.record(&source(&format!("app-{}", i % 2)), "warn");
+        }
+        tracker.record(&source("app-0"), "INFO");
+        tracker.rotate()
+    }
+
+    #[test]
+    fn spike_needs_history_min_count_and_factor() {
+        let config = RateConfig { baseline_buckets: 4, factor: 5.0, min_count: 10, ..RateConfig::default() };
+        let tracker = RateTracker::new(config);
+
+        // No baseline yet.
+        assert!(bucket(&tracker, 50).is_empty());
+        assert!(bucket(&tracker, 2).is_empty());
+        // Median of [50, 2] is 26, 5x is 130.
+        assert!(bucket(&tracker, 100).is_empty());
+        bucket(&tracker, 3);
+        assert!(bucket(&tracker, 9).is_empty(), "below min_count");
+
+        // The 50 has aged out, median of [2, 100, 3, 9] is 6.
+        let spikes = bucket(&tracker, 60);
+        assert_eq!(spikes.len(), 1);
+        let (spike, source) = &spikes[0];
+        assert_eq!(spike.level, "WARN");
+        assert_eq!(spike.count, 60);
+        assert_eq!(spike.history.last(), Some(&60));
+        assert_eq!(spike.pods, vec!["app-0", "app-1"]);
+        assert_eq!(source.pod, "app-0");
+    }
+
+    #[test]
+    fn level_aliases_are_counted_as_the_configured_level() {
+        let tracker = RateTracker::new(RateConfig::default());
+        tracker.record(&source("app-0"), "WARNING");
+        tracker.record(&source("app-0"), "warn");
+        tracker.record(&source("app-0"), "ERROR");
+        let series = tracker.series.lock().unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[&("helved".into(), "app".into(), "WARN".into())].current, 2);
+    }
+
+    #[test]
+    fn median_of_even_and_odd_lengths() {
+        assert_eq!(median(&VecDeque::from([3, 1, 2])), Some(2.0));
+        assert_eq!(median(&VecDeque::from([4, 1, 2, 3])), Some(2.5));
+        assert_eq!(median(&VecDeque::new()), None);
+    }
+}
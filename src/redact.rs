@@ -0,0 +1,281 @@
+use std::borrow::Cow;
+
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+/// Built-in rules, in the order they are applied. Disable any of them by name with
+/// `REDACT_DISABLE`.
+const BUILTIN: &[&str] = &["jwt", "bearer", "email", "iban", "fnr", "account"];
+
+/// Scrubs personal data and credentials from log text before it is aggregated, logged
+/// by us, or rendered into Slack messages and links.
+pub struct Redactor {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    regex: Regex,
+    kind: Kind,
+}
+
+enum Kind {
+    /// Replace every match with a fixed string.
+    Replace(String),
+    /// Replace matches that pass the check, e.g. a checksum, with a fixed string.
+    Checked(fn(&str) -> bool, &'static str),
+}
+
+/// Extra rule from `REDACT_PATTERNS`, matches are replaced by `<name>`.
+#[derive(Deserialize)]
+pub struct Pattern {
+    pub name: String,
+    pub pattern: String,
+}
+
+impl Redactor {
+    pub fn new(disabled: &[String], extra: Vec<Pattern>) -> Result<Self> {
+        let mut rules = Vec::new();
+        for name in BUILTIN.iter().filter(|n| !disabled.iter().any(|d| d == *n)) {
+            rules.push(builtin(name));
+        }
+        for pattern in extra {
+            rules.push(Rule {
+                regex: Regex::new(&pattern.pattern)
+                    .context(format!("invalid redact pattern {}", pattern.name))?,
+                kind: Kind::Replace(format!("<{}>", pattern.name)),
+            });
+        }
+        Ok(Redactor { rules })
+    }
+
+    /// `REDACT_DISABLE` is a comma separated list of built-in rules to skip,
+    /// `REDACT_PATTERNS` a JSON array of `{ "name": .., "pattern": .. }`.
+    pub fn from_env() -> Result<Self> {
+        let disabled: Vec<String> = std::env::var("REDACT_DISABLE")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+        let extra = match std::env::var("REDACT_PATTERNS") {
+            Ok(json) if !json.trim().is_empty() => {
+                serde_json::from_str(&json).context("invalid REDACT_PATTERNS")?
+            }
+            _ => Vec::new(),
+        };
+        Redactor::new(&disabled, extra)
+    }
+
+    pub fn redact(&self, input: &str) -> String {
+        let mut text = input.to_string();
+        for rule in &self.rules {
+            let replaced = match &rule.kind {
+                Kind::Replace(with) => rule.regex.replace_all(&text, with.as_str()),
+                Kind::Checked(valid, with) => rule.regex.replace_all(&text, |c: &Captures| {
+                    if valid(&c[0]) { with.to_string() } else { c[0].to_string() }
+                }),
+            };
+            if let Cow::Owned(replaced) = replaced {
+                text = replaced;
+            }
+        }
+        text
+    }
+}
+
+fn builtin(name: &str) -> Rule {
+    let (pattern, kind) = match name {
+        "jwt" => (
+            r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]*",
+            Kind::Replace("<jwt>".into()),
+        ),
+        "bearer" => (
+            r"(?i)\bbearer\s+[A-Za-z0-9._~+/=-]+",
+            Kind::Replace("Bearer <token>".into()),
+        ),
+        "email" => (
+            r"(?i)\b[A-Z0-9._%+-]+@[A-Z0-9.-]+\.[A-Z]{2,}\b",
+            Kind::Replace("<email>".into()),
+        ),
+        "iban" => (
+            r"\b[A-Z]{2}\d{2}(?: ?[A-Z0-9]{4}){2,7}(?: ?[A-Z0-9]{1,3})?\b",
+            Kind::Checked(is_iban, "<iban>"),
+        ),
+        "account" => (r"\b(?:\d{4}\.\d{2}\.\d{5}|\d{11})\b", Kind::Checked(is_account, "<account>")),
+        "fnr" => (r"\b\d{6} ?\d{5}\b", Kind::Checked(is_fnr, "<fnr>")),
+        _ => unreachable!("unknown builtin redact rule {name}"),
+    };
+    Rule {
+        regex: Regex::new(pattern).expect("builtin redact pattern"),
+        kind,
+    }
+}
+
+fn digits(s: &str) -> Vec<u32> {
+    s.chars().filter_map(|c| c.to_digit(10)).collect()
+}
+
+/// Mod-11 control digit with the given weights, `None` when the remainder makes the
+/// number invalid.
+fn mod11(d: &[u32], weights: &[u32]) -> Option<u32> {
+    let sum: u32 = d.iter().zip(weights).map(|(a, b)| a * b).sum();
+    match 11 - sum % 11 {
+        11 => Some(0),
+        10 => None,
+        k => Some(k),
+    }
+}
+
+/// Fødselsnummer, D-number (day + 40), H-number (month + 40) or synthetic test
+/// number (month + 80), validated by both control digits.
+fn is_fnr(s: &str) -> bool {
+    let d = digits(s);
+    if d.len() != 11 {
+        return false;
+    }
+    let day = d[0] * 10 + d[1];
+    let month = d[2] * 10 + d[3];
+    let day_ok = (1..=31).contains(&(day % 40)) && day < 72;
+    let month_ok = (1..=12).contains(&(month % 40)) && month < 93;
+    if !day_ok || !month_ok {
+        return false;
+    }
+    let Some(k1) = mod11(&d[..9], &[3, 7, 6, 1, 8, 9, 4, 5, 2]) else { return false };
+    let mut first_ten = d[..9].to_vec();
+    first_ten.push(k1);
+    let Some(k2) = mod11(&first_ten, &[5, 4, 3, 2, 7, 6, 5, 4, 3, 2]) else { return false };
+    d[9] == k1 && d[10] == k2
+}
+
+/// Account numbers written with dots are redacted as is, plain ones only if the
+/// checksum holds. Every valid fødselsnummer also passes that checksum, so those are
+/// left to the `fnr` rule and `REDACT_DISABLE=fnr` really keeps them.
+fn is_account(s: &str) -> bool {
+    s.contains('.') || (is_account_number(s) && !is_fnr(s))
+}
+
+/// Norwegian bank account number, validated by its mod-11 control digit.
+fn is_account_number(s: &str) -> bool {
+    let d = digits(s);
+    d.len() == 11 && mod11(&d[..10], &[5, 4, 3, 2, 7, 6, 5, 4, 3, 2]) == Some(d[10])
+}
+
+fn is_iban(s: &str) -> bool {
+    let compact: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    let rearranged = format!("{}{}", &compact[4..], &compact[..4]);
+    let mut remainder = 0u32;
+    for c in rearranged.chars() {
+        let Some(value) = c.to_digit(36) else { return false };
+        for digit in value.to_string().chars() {
+            remainder = (remainder * 10 + digit.to_digit(10).unwrap_or(0)) % 97;
+        }
+    }
+    remainder == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{AlertView, Log};
+    use chrono::Utc;
+    use std::collections::HashSet;
+
+    fn redactor() -> Redactor {
+        Redactor::new(&[], Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn fnr_and_d_number_are_checksum_validated() {
+        assert!(is_fnr("01018012371"));
+        assert!(is_fnr("41018012365"));
+        assert!(!is_fnr("01018012372"));
+        assert!(!is_fnr("12345678903"));
+        assert!(is_account_number("12345678903"));
+    }
+
+    #[test]
+    fn redacts_builtin_kinds() {
+        let r = redactor();
+        assert_eq!(r.redact("fnr 01018012371 ok"), "fnr <fnr> ok");
+        assert_eq!(r.redact("fnr 010180 12371"), "fnr <fnr>");
+        assert_eq!(r.redact("konto 12345678903 / 1234.56.78903"), "konto <account> / <account>");
+        assert_eq!(r.redact("iban NO9386011117947"), "iban <iban>");
+        assert_eq!(r.redact("mail ola.nordmann@nav.no"), "mail <email>");
+        assert_eq!(r.redact("Authorization: Bearer abc.def-ghi"), "Authorization: Bearer <token>");
+        assert_eq!(r.redact("token eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiIxIn0.sig_x-1"), "token <jwt>");
+        // Not PII: wrong checksum, too short, or ordinary ids.
+        assert_eq!(r.redact("id 01018012372 count 12345"), "id 01018012372 count 12345");
+    }
+
+    #[test]
+    fn fnr_and_account_rules_toggle_independently() {
+        let text = "fnr 01018012371 konto 12345678903 / 1234.56.78903";
+        let without_account = Redactor::new(&["account".to_string()], Vec::new()).unwrap();
+        assert_eq!(without_account.redact(text), "fnr <fnr> konto 12345678903 / 1234.56.78903");
+        let without_fnr = Redactor::new(&["fnr".to_string()], Vec::new()).unwrap();
+        assert_eq!(without_fnr.redact(text), "fnr 01018012371 konto <account> / <account>");
+    }
+
+    #[test]
+    fn custom_patterns_and_disabled_rules() {
+        let extra = vec![Pattern { name: "sak".into(), pattern: r"SAK-\d+".into() }];
+        let r = Redactor::new(&["email".to_string()], extra).unwrap();
+        assert_eq!(r.redact("SAK-42 for a@b.no"), "<sak> for a@b.no");
+    }
+
+    #[test]
+    fn thread_logger_and_error_type_are_redacted() {
+        let mut log: Log = serde_json::from_value(serde_json::json!({
+            "level": "ERROR",
+            "message": "feilet",
+            "thread_name": "worker kari@nav.no",
+            "logger_name": "audit.01018012371",
+            "error.type": "NotFound for 01018012371",
+        }))
+        .unwrap();
+        log.redact(&redactor());
+        assert_eq!(log.thread_name(), Some("worker <email>"));
+        assert_eq!(log.logger_name(), Some("audit.<fnr>"));
+        assert_eq!(log.exception_class(), Some("NotFound for <fnr>"));
+    }
+
+    #[test]
+    fn nothing_unredacted_reaches_slack_or_links() {
+        let fnr = "01018012371";
+        let email = "kari@nav.no";
+        let mut log: Log = serde_json::from_value(serde_json::json!({
+            "level": "ERROR",
+            "message": format!("Fant ikke person {fnr} ({email}) med Bearer eyJa.eyJb.c"),
+        }))
+        .unwrap();
+        log.redact(&redactor());
+
+        let pods = HashSet::from(["app-1".to_string()]);
+        let traces = HashSet::new();
+        let view = AlertView {
+            sample: &log,
+            cluster: "prod-gcp",
+            namespace: "helved",
+            container: "app",
+            count: 1,
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            pods: &pods,
+            trace_ids: &traces,
+            key: "helved/app||1",
+            buffered: 0,
+            acked_by: None,
+            muted: None,
+            resolved_after: None,
+            resolved_by: None,
+            in_app: &[],
+        };
+        let rendered = format!("{} {}", view.to_blocks(), view.fallback_text());
+        let decoded = urlencoding::decode(&rendered).unwrap().into_owned();
+        for needle in [fnr, email, "eyJa"] {
+            assert!(!rendered.contains(needle), "{needle} leaked into {rendered}");
+            assert!(!decoded.contains(needle), "{needle} leaked into a link");
+        }
+    }
+}
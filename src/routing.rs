@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+use crate::model::Log;
+
+/// One entry in `SLACK_ROUTES`. Every matcher that is set must match (full match,
+/// regex syntax) for the route to apply. Routes are tried in order and the first
+/// match wins, unless it sets `continue` to also collect channels from later routes.
+#[derive(Deserialize, Debug)]
+pub struct Route {
+    pub channels: Vec<String>,
+    #[serde(default, deserialize_with = "anchored")]
+    pub namespace: Option<Regex>,
+    #[serde(default, deserialize_with = "anchored")]
+    pub container: Option<Regex>,
+    #[serde(default, deserialize_with = "anchored")]
+    pub logger: Option<Regex>,
+    #[serde(default, deserialize_with = "anchored")]
+    pub cluster: Option<Regex>,
+    #[serde(default, deserialize_with = "anchored")]
+    pub severity: Option<Regex>,
+    /// Unanchored, matches anywhere in the message.
+    #[serde(default, deserialize_with = "unanchored")]
+    pub message: Option<Regex>,
+    #[serde(default, rename = "continue")]
+    pub continue_matching: bool,
+}
+
+/// Decides which Slack channels an aggregate is posted to.
+pub struct Router {
+    routes: Vec<Route>,
+    default_channels: Vec<String>,
+    cluster: String,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>, default_channels: Vec<String>, cluster: String) -> Self {
+        Router { routes, default_channels, cluster }
+    }
+
+    /// Routes from `SLACK_ROUTES` (a JSON array), falling back to `SLACK_CHANNEL`.
+    pub fn from_env() -> Result<Self> {
+        let routes = match std::env::var("SLACK_ROUTES") {
+            Ok(json) if !json.trim().is_empty() => {
+                serde_json::from_str(&json).context("invalid SLACK_ROUTES")?
+            }
+            _ => Vec::new(),
+        };
+        Ok(Router::new(
+            routes,
+            vec![crate::env("SLACK_CHANNEL")],
+            crate::env("NAIS_CLUSTER_NAME"),
+        ))
+    }
+
+    pub fn channels(&self, namespace: &str, container: &str, log: &Log) -> Vec<String> {
+        let mut channels: Vec<String> = Vec::new();
+        for route in &self.routes {
+            if !route.matches(namespace, container, &self.cluster, log) {
+                continue;
+            }
+            for channel in &route.channels {
+                if !channels.contains(channel) {
+                    channels.push(channel.clone());
+                }
+            }
+            if !route.continue_matching {
+                break;
+            }
+        }
+        if channels.is_empty() {
+            channels = self.default_channels.clone();
+        }
+        channels
+    }
+}
+
+impl Route {
+    fn matches(&self, namespace: &str, container: &str, cluster: &str, log: &Log) -> bool {
+        let is = |matcher: &Option<Regex>, value: &str| matcher.as_ref().is_none_or(|re| re.is_match(value));
+        is(&self.namespace, namespace)
+            && is(&self.container, container)
+            && is(&self.logger, log.logger_name().unwrap_or(""))
+            && is(&self.cluster, cluster)
+            && is(&self.severity, log.level())
+            && is(&self.message, log.message())
+    }
+}
+
+pub(crate) fn anchored<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+    let Some(pattern) = Option::<String>::deserialize(d)? else { return Ok(None) };
+    Regex::new(&format!("^(?:{pattern})$"))
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+pub(crate) fn unanchored<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+    let Some(pattern) = Option::<String>::deserialize(d)? else { return Ok(None) };
+    Regex::new(&pattern).map(Some).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(message: &str) -> Log {
+        serde_json::from_value(serde_json::json!({
+            "level": "ERROR",
+            "logger_name": "no.nav.helved.Payment",
+            "message": message,
+        }))
+        .unwrap()
+    }
+
+    fn router(cluster: &str) -> Router {
+        let routes = serde_json::from_str(
+            r##"[
+                { "cluster": "prod-gcp", "container": "payment-.*", "channels": ["#hel-ved-prod-alerts"], "continue": true },
+                { "message": "(?i)timeout", "channels": ["#hel-ved-timeouts"] },
+                { "cluster": "dev-gcp", "channels": ["#hel-ved-dev-noise"] }
+            ]"##,
+        )
+        .unwrap();
+        Router::new(routes, vec!["#default".into()], cluster.into())
+    }
+
+    #[test]
+    fn first_match_wins_unless_continue() {
+        let prod = router("prod-gcp");
+        assert_eq!(
+            prod.channels("helved", "payment-api", &log("read Timeout")),
+            vec!["#hel-ved-prod-alerts", "#hel-ved-timeouts"]
+        );
+        assert_eq!(prod.channels("helved", "payment-api", &log("boom")), vec!["#hel-ved-prod-alerts"]);
+        assert_eq!(prod.channels("helved", "other", &log("boom")), vec!["#default"]);
+
+        let dev = router("dev-gcp");
+        assert_eq!(dev.channels("helved", "payment-api", &log("boom")), vec!["#hel-ved-dev-noise"]);
+    }
+
+    #[test]
+    fn matchers_are_anchored() {
+        let prod = router("prod-gcp");
+        assert_eq!(prod.channels("helved", "my-payment-api", &log("boom")), vec!["#default"]);
+    }
+}
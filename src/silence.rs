@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::model::Log;
+
+/// What a silence applies to. Every matcher that is set must match. `key` is compared
+/// as is, `namespace`, `container` and `logger` are full-match regexes and `message`
+/// matches anywhere, like in `SLACK_ROUTES`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Matchers {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logger: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Stops matching errors from being posted until it expires. Suppressed events are
+/// still counted, per aggregation key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Silence {
+    pub id: String,
+    #[serde(flatten)]
+    pub matchers: Matchers,
+    pub created_by: String,
+    #[serde(default)]
+    pub comment: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    #[serde(default)]
+    pub suppressed: BTreeMap<String, u64>,
+}
+
+/// Request to create a silence. Expiry is either absolute or a duration from now.
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewSilence {
+    #[serde(flatten)]
+    pub matchers: Matchers,
+    pub created_by: String,
+    #[serde(default)]
+    pub comment: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub duration_minutes: Option<i64>,
+}
+
+struct Compiled {
+    namespace: Option<Regex>,
+    container: Option<Regex>,
+    logger: Option<Regex>,
+    message: Option<Regex>,
+}
+
+impl Matchers {
+    fn compile(&self) -> Result<Compiled> {
+        if *self == Matchers::default() {
+            bail!("a silence needs at least one matcher");
+        }
+        let anchored = |p: &Option<String>| -> Result<Option<Regex>> {
+            p.as_ref()
+                .map(|p| Regex::new(&format!("^(?:{p})$")).context(format!("invalid matcher {p}")))
+                .transpose()
+        };
+        Ok(Compiled {
+            namespace: anchored(&self.namespace)?,
+            container: anchored(&self.container)?,
+            logger: anchored(&self.logger)?,
+            message: self
+                .message
+                .as_ref()
+                .map(|p| Regex::new(p).context(format!("invalid matcher {p}")))
+                .transpose()?,
+        })
+    }
+}
+
+/// The silences currently in effect.
+#[derive(Default)]
+pub struct Silences {
+    active: Vec<(Silence, Compiled)>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Longest a silence may last. Anything longer is better fixed in the code or routing.
+const MAX_DAYS: i64 = 90;
+
+impl Silences {
+    pub fn add(&mut self, new: NewSilence, now: DateTime<Utc>) -> Result<Silence> {
+        let expires_at = match (new.expires_at, new.duration_minutes) {
+            (Some(at), _) => at,
+            (None, Some(minutes)) => Duration::try_minutes(minutes)
+                .and_then(|d| now.checked_add_signed(d))
+                .context("duration_minutes is out of range")?,
+            (None, None) => bail!("a silence needs expires_at or duration_minutes"),
+        };
+        if expires_at <= now {
+            bail!("a silence must expire in the future");
+        }
+        if expires_at > now + Duration::days(MAX_DAYS) {
+            bail!("a silence can last at most {MAX_DAYS} days");
+        }
+        let silence = Silence {
+            id: format!(
+                "{:x}-{}",
+                now.timestamp_millis(),
+                NEXT_ID.fetch_add(1, Ordering::Relaxed)
+            ),
+            matchers: new.matchers,
+            created_by: new.created_by,
+            comment: new.comment,
+            created_at: now,
+            expires_at,
+            suppressed: BTreeMap::new(),
+        };
+        self.restore(silence.clone())?;
+        Ok(silence)
+    }
+
+    /// Put back a silence loaded from the state backend.
+    pub fn restore(&mut self, silence: Silence) -> Result<()> {
+        let compiled = silence.matchers.compile()?;
+        self.active.push((silence, compiled));
+        Ok(())
+    }
+
+    /// Expire a silence right away. False if there is no such silence.
+    pub fn expire(&mut self, id: &str) -> bool {
+        let before = self.active.len();
+        self.active.retain(|(s, _)| s.id != id);
+        self.active.len() != before
+    }
+
+    /// Drop silences that have expired. True if there were any.
+    pub fn prune(&mut self, now: DateTime<Utc>) -> bool {
+        let before = self.active.len();
+        self.active.retain(|(s, _)| s.expires_at > now);
+        self.active.len() != before
+    }
+
+    /// Unexpired silences, oldest first.
+    pub fn list(&mut self, now: DateTime<Utc>) -> Vec<Silence> {
+        self.prune(now);
+        self.active.iter().map(|(s, _)| s.clone()).collect()
+    }
+
+    /// If a silence matches, count the event against it and return its id.
+    pub fn suppress(
+        &mut self,
+        key: &str,
+        namespace: &str,
+        container: &str,
+        log: &Log,
+        now: DateTime<Utc>,
+    ) -> Option<String> {
+        let is = |m: &Option<Regex>, value: &str| m.as_ref().is_none_or(|re| re.is_match(value));
+        let (silence, _) = self.active.iter_mut().find(|(s, c)| {
+            s.expires_at > now
+                && s.matchers.key.as_deref().is_none_or(|k| k == key)
+                && is(&c.namespace, namespace)
+                && is(&c.container, container)
+                && is(&c.logger, log.logger_name().unwrap_or(""))
+                && is(&c.message, log.message())
+        })?;
+        *silence.suppressed.entry(key.to_string()).or_default() += 1;
+        Some(silence.id.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(message: &str) -> Log {
+        serde_json::from_value(serde_json::json!({ "level": "ERROR", "message": message })).unwrap()
+    }
+
+    fn new(json: serde_json::Value) -> NewSilence {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn matches_until_expiry_and_counts_suppressed() {
+        let now = Utc::now();
+        let mut silences = Silences::default();
+        let silence = silences
+            .add(new(serde_json::json!({ "container": "app", "message": "timeout", "created_by": "kari", "duration_minutes": 60 })), now)
+            .unwrap();
+
+        assert!(silences.suppress("k1", "helved", "app", &log("read timeout"), now).is_some());
+        assert!(silences.suppress("k1", "helved", "app", &log("read timeout"), now).is_some());
+        assert!(silences.suppress("k2", "helved", "app", &log("boom"), now).is_none());
+        assert!(silences.suppress("k1", "helved", "app-2", &log("read timeout"), now).is_none());
+        assert_eq!(silences.list(now)[0].suppressed["k1"], 2);
+
+        let later = now + Duration::minutes(61);
+        assert!(silences.suppress("k1", "helved", "app", &log("read timeout"), later).is_none());
+        assert!(silences.prune(later));
+        assert!(!silences.prune(later));
+        assert!(silences.list(later).is_empty());
+        assert!(!silences.expire(&silence.id));
+    }
+
+    #[test]
+    fn rejects_silences_that_match_everything_or_never_expire() {
+        let now = Utc::now();
+        let mut silences = Silences::default();
+        assert!(silences.add(new(serde_json::json!({ "created_by": "kari", "duration_minutes": 60 })), now).is_err());
+        assert!(silences.add(new(serde_json::json!({ "key": "k", "created_by": "kari" })), now).is_err());
+        assert!(silences.add(new(serde_json::json!({ "logger": "(", "created_by": "kari", "duration_minutes": 1 })), now).is_err());
+        assert!(silences.add(new(serde_json::json!({ "key": "k", "created_by": "kari", "duration_minutes": i64::MAX })), now).is_err());
+        assert!(silences.add(new(serde_json::json!({ "key": "k", "created_by": "kari", "duration_minutes": 200_000 })), now).is_err());
+        assert!(silences.add(new(serde_json::json!({ "key": "k", "created_by": "kari", "expires_at": "9999-01-01T00:00:00Z" })), now).is_err());
+    }
+}
@@ -1,14 +1,27 @@
+use std::collections::{HashMap, VecDeque};
+
 use anyhow::{anyhow, Result};
 use serde::Deserialize;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Duration, Instant};
+
+use crate::metrics::METRICS;
+use crate::notifier::{Notifier, PostedMessage};
 
 const SLACK_POST_URL: &str = "https://slack.com/api/chat.postMessage";
 const SLACK_UPDATE_URL: &str = "https://slack.com/api/chat.update";
+const SLACK_REACTIONS_ADD_URL: &str = "https://slack.com/api/reactions.add";
+const SLACK_AUTH_TEST_URL: &str = "https://slack.com/api/auth.test";
 
-#[derive(Clone, Debug)]
-pub struct PostedMessage {
-    pub channel: String,
-    pub ts: String,
-}
+/// Colour of the attachment bar on resolved alerts.
+const RESOLVED_COLOR: &str = "#2eb886";
+
+/// Give up on a call after this many transient failures.
+const MAX_ATTEMPTS: u32 = 5;
+/// Give up on a call once Slack has rate limited it this many times.
+const MAX_RATE_LIMITED: u32 = 5;
+/// Used when Slack rate limits us without a Retry-After header.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
 
 #[derive(Deserialize)]
 struct SlackResponse {
@@ -21,60 +34,90 @@ struct SlackResponse {
     channel: Option<String>,
 }
 
+/// A call Slack answered with `ok: false`, carrying its error code, e.g. `already_reacted`.
+#[derive(Debug)]
+pub struct SlackError {
+    method: &'static str,
+    pub code: String,
+}
+
+impl std::fmt::Display for SlackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "slack {} failed: {}", self.method, self.code)
+    }
+}
+
+impl std::error::Error for SlackError {}
+
+impl SlackError {
+    /// Whether `e` is a Slack error with the given code.
+    fn is(e: &anyhow::Error, code: &str) -> bool {
+        e.downcast_ref::<SlackError>().is_some_and(|e| e.code == code)
+    }
+}
+
+/// Handle to the Slack dispatcher. Every call is queued and sent by a single task that
+/// honours Retry-After, spaces out calls per method and retries transient failures.
 pub struct Slack {
-    token: String,
-    channel: String,
-    http: reqwest::Client,
+    calls: mpsc::UnboundedSender<Call>,
+    /// Reaction added to an alert when it resolves, e.g. `white_check_mark`.
+    resolved_reaction: Option<String>,
 }
 
 impl Default for Slack {
     fn default() -> Self {
-        Slack {
-            token: crate::env("SLACK_BOT_TOKEN"),
-            channel: crate::env("SLACK_CHANNEL"),
-            http: reqwest::Client::new(),
-        }
+        let resolved_reaction = std::env::var("SLACK_RESOLVED_REACTION")
+            .ok()
+            .filter(|r| !r.is_empty());
+        Slack::new(crate::env("SLACK_BOT_TOKEN"), resolved_reaction)
     }
 }
 
 impl Slack {
-    pub async fn post(
+    pub fn new(token: String, resolved_reaction: Option<String>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let dispatcher = Dispatcher {
+            token,
+            http: reqwest::Client::new(),
+            calls: rx,
+            queue: Queue::default(),
+            paused_until: None,
+        };
+        tokio::spawn(dispatcher.run());
+        Slack {
+            calls: tx,
+            resolved_reaction,
+        }
+    }
+
+    async fn post_message(
         &self,
+        channel: &str,
+        thread_ts: Option<&str>,
         blocks: serde_json::Value,
         fallback_text: &str,
     ) -> Result<PostedMessage> {
-        let body = serde_json::json!({
-            "channel": self.channel,
+        let mut body = serde_json::json!({
+            "channel": channel,
             "text": fallback_text,
             "blocks": blocks,
         });
-
-        let resp: SlackResponse = self
-            .http
-            .post(SLACK_POST_URL)
-            .bearer_auth(&self.token)
-            .json(&body)
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        if !resp.ok {
-            return Err(anyhow!(
-                "slack chat.postMessage failed: {}",
-                resp.error.unwrap_or_else(|| "unknown error".into())
-            ));
+        if let Some(thread_ts) = thread_ts {
+            body["thread_ts"] = thread_ts.into();
         }
 
+        let resp = self.call(Method::PostMessage, body).await?;
+
         Ok(PostedMessage {
-            channel: resp.channel.unwrap_or_else(|| self.channel.clone()),
+            channel: resp.channel.unwrap_or_else(|| channel.to_string()),
             ts: resp
                 .ts
                 .ok_or_else(|| anyhow!("slack postMessage returned ok but no ts"))?,
         })
     }
 
-    pub async fn update(
+    /// Queued updates of the same message are coalesced, so only the latest content is sent.
+    async fn update_message(
         &self,
         posted: &PostedMessage,
         blocks: serde_json::Value,
@@ -87,23 +130,437 @@ impl Slack {
             "blocks": blocks,
         });
 
-        let resp: SlackResponse = self
+        self.call(Method::Update, body).await?;
+        Ok(())
+    }
+
+    async fn call(&self, method: Method, body: serde_json::Value) -> Result<SlackResponse> {
+        let (reply, rx) = oneshot::channel();
+        let call = Call {
+            method,
+            body,
+            reply,
+            attempts: 0,
+            rate_limited: 0,
+            not_before: Instant::now(),
+        };
+        self.calls
+            .send(call)
+            .map_err(|_| anyhow!("slack dispatcher has stopped"))?;
+        // Queued behind rate limits, so callers' liveness deadlines don't count this.
+        crate::health::waiting(rx)
+            .await
+            .map_err(|_| anyhow!("slack dispatcher dropped {}", method.name()))?
+    }
+}
+
+impl Notifier for Slack {
+    async fn create(
+        &self,
+        channel: &str,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<PostedMessage> {
+        self.post_message(channel, None, blocks, fallback_text).await
+    }
+
+    async fn update(
+        &self,
+        message: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<()> {
+        self.update_message(message, blocks, fallback_text).await
+    }
+
+    async fn reply(
+        &self,
+        parent: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<PostedMessage> {
+        self.post_message(&parent.channel, Some(&parent.ts), blocks, fallback_text)
+            .await
+    }
+
+    /// Moves the blocks into a green attachment so the message no longer looks like an
+    /// active fire, and adds the resolved reaction if one is configured.
+    async fn resolve(
+        &self,
+        message: &PostedMessage,
+        blocks: serde_json::Value,
+        fallback_text: &str,
+    ) -> Result<()> {
+        let body = serde_json::json!({
+            "channel": message.channel,
+            "ts": message.ts,
+            "text": fallback_text,
+            "blocks": [],
+            "attachments": [ { "color": RESOLVED_COLOR, "blocks": blocks } ],
+        });
+        self.call(Method::Update, body).await?;
+
+        if let Some(reaction) = &self.resolved_reaction {
+            let body = serde_json::json!({
+                "channel": message.channel,
+                "timestamp": message.ts,
+                "name": reaction,
+            });
+            match self.call(Method::ReactionsAdd, body).await {
+                Err(e) if SlackError::is(&e, "already_reacted") => {}
+                other => {
+                    other?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails once the token is revoked or otherwise invalid.
+    async fn check(&self) -> Result<()> {
+        self.call(Method::AuthTest, serde_json::json!({})).await?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Method {
+    PostMessage,
+    Update,
+    ReactionsAdd,
+    AuthTest,
+}
+
+impl Method {
+    fn name(self) -> &'static str {
+        match self {
+            Method::PostMessage => "chat.postMessage",
+            Method::Update => "chat.update",
+            Method::ReactionsAdd => "reactions.add",
+            Method::AuthTest => "auth.test",
+        }
+    }
+
+    fn url(self) -> &'static str {
+        match self {
+            Method::PostMessage => SLACK_POST_URL,
+            Method::Update => SLACK_UPDATE_URL,
+            Method::ReactionsAdd => SLACK_REACTIONS_ADD_URL,
+            Method::AuthTest => SLACK_AUTH_TEST_URL,
+        }
+    }
+
+    /// Spacing between calls of a method, across all channels. From Slack's documented
+    /// limits: postMessage is about one per second per channel, which we keep to for the
+    /// whole app since alerts rarely spread over many channels at once; chat.update and
+    /// reactions.add are tier 3 (50+ per minute) and auth.test is tier 4 (100+ per minute).
+    fn min_interval(self) -> Duration {
+        match self {
+            Method::PostMessage | Method::AuthTest => Duration::from_millis(1000),
+            Method::Update | Method::ReactionsAdd => Duration::from_millis(1200),
+        }
+    }
+}
+
+struct Call {
+    method: Method,
+    body: serde_json::Value,
+    reply: oneshot::Sender<Result<SlackResponse>>,
+    attempts: u32,
+    /// Times Slack answered this call with a rate limit.
+    rate_limited: u32,
+    not_before: Instant,
+}
+
+impl Call {
+    /// Count a rate limited attempt. False once the call should give up.
+    fn rate_limited(&mut self) -> bool {
+        self.rate_limited += 1;
+        self.rate_limited < MAX_RATE_LIMITED
+    }
+
+    /// Identity of the message a chat.update targets.
+    fn update_key(&self) -> Option<(&str, &str)> {
+        if self.method != Method::Update {
+            return None;
+        }
+        Some((self.body["channel"].as_str()?, self.body["ts"].as_str()?))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    calls: VecDeque<Call>,
+    next_allowed: HashMap<Method, Instant>,
+}
+
+impl Queue {
+    fn push(&mut self, call: Call) {
+        if let Some(key) = call.update_key()
+            && let Some(queued) = self.calls.iter_mut().find(|c| c.update_key() == Some(key))
+        {
+            // Newer content wins; the superseded caller is told its update went through.
+            let superseded = std::mem::replace(&mut queued.reply, call.reply);
+            queued.body = call.body;
+            let _ = superseded.send(Ok(SlackResponse {
+                ok: true,
+                error: None,
+                ts: None,
+                channel: None,
+            }));
+            return;
+        }
+        self.calls.push_back(call);
+    }
+
+    /// Put a call back at the front after a failed attempt.
+    fn retry(&mut self, call: Call) {
+        self.calls.push_front(call);
+    }
+
+    /// The first call that may be sent now, or the earliest time one can be sent.
+    fn pop_ready(&mut self, now: Instant) -> Result<Call, Option<Instant>> {
+        let ready_at = |c: &Call| {
+            let allowed = self.next_allowed.get(&c.method).copied().unwrap_or(now);
+            c.not_before.max(allowed)
+        };
+        match self.calls.iter().position(|c| ready_at(c) <= now) {
+            Some(i) => {
+                let call = self.calls.remove(i).expect("index from position");
+                self.next_allowed
+                    .insert(call.method, now + call.method.min_interval());
+                Ok(call)
+            }
+            None => Err(self.calls.iter().map(ready_at).min()),
+        }
+    }
+}
+
+enum Failure {
+    RateLimited(Duration),
+    Transient(anyhow::Error),
+    Permanent(anyhow::Error),
+}
+
+struct Dispatcher {
+    token: String,
+    http: reqwest::Client,
+    calls: mpsc::UnboundedReceiver<Call>,
+    queue: Queue,
+    paused_until: Option<Instant>,
+}
+
+impl Dispatcher {
+    async fn run(mut self) {
+        loop {
+            while let Ok(call) = self.calls.try_recv() {
+                self.queue.push(call);
+            }
+
+            let now = Instant::now();
+            let wake_at = match self.paused_until.filter(|t| *t > now) {
+                Some(paused_until) => Some(paused_until),
+                None => match self.queue.pop_ready(now) {
+                    Ok(call) => {
+                        self.send(call).await;
+                        continue;
+                    }
+                    Err(wake_at) => wake_at,
+                },
+            };
+
+            // Nothing to send yet: wait for new calls or until the next one is due.
+            let sleep = async {
+                match wake_at {
+                    Some(t) => tokio::time::sleep_until(t).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                call = self.calls.recv() => match call {
+                    Some(call) => self.queue.push(call),
+                    None => return,
+                },
+                _ = sleep => {}
+            }
+        }
+    }
+
+    async fn send(&mut self, mut call: Call) {
+        let started = Instant::now();
+        let result = self.execute(call.method, &call.body).await;
+        METRICS.slack_call(call.method.name(), started.elapsed());
+        match result {
+            Ok(resp) => {
+                let _ = call.reply.send(Ok(resp));
+            }
+            Err(Failure::RateLimited(retry_after)) => {
+                log::warn!(
+                    "slack {} rate limited, pausing for {:?}",
+                    call.method.name(),
+                    retry_after
+                );
+                self.paused_until = Some(Instant::now() + retry_after);
+                if call.rate_limited() {
+                    self.queue.retry(call);
+                } else {
+                    let _ = call.reply.send(Err(anyhow!(
+                        "slack {} still rate limited after {} attempts",
+                        call.method.name(),
+                        MAX_RATE_LIMITED
+                    )));
+                }
+            }
+            Err(Failure::Transient(e)) if call.attempts + 1 < MAX_ATTEMPTS => {
+                call.attempts += 1;
+                let backoff = backoff(call.attempts);
+                log::warn!(
+                    "slack {} failed (attempt {}), retrying in {:?}: {}",
+                    call.method.name(),
+                    call.attempts,
+                    backoff,
+                    e
+                );
+                call.not_before = Instant::now() + backoff;
+                self.queue.retry(call);
+            }
+            Err(Failure::Transient(e)) | Err(Failure::Permanent(e)) => {
+                let _ = call.reply.send(Err(e));
+            }
+        }
+    }
+
+    async fn execute(
+        &self,
+        method: Method,
+        body: &serde_json::Value,
+    ) -> std::result::Result<SlackResponse, Failure> {
+        let resp = self
             .http
-            .post(SLACK_UPDATE_URL)
+            .post(method.url())
             .bearer_auth(&self.token)
-            .json(&body)
+            .json(body)
             .send()
-            .await?
-            .json()
-            .await?;
+            .await
+            .map_err(|e| {
+                METRICS.slack_failure(method.name(), "request_failed");
+                Failure::Transient(e.into())
+            })?;
+
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RETRY_AFTER);
+        let status = resp.status();
+        if !status.is_success() {
+            METRICS.slack_failure(method.name(), &format!("http_{}", status.as_u16()));
+        }
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(Failure::RateLimited(retry_after));
+        }
+        if status.is_server_error() {
+            return Err(Failure::Transient(anyhow!(
+                "slack {} returned {}",
+                method.name(),
+                status
+            )));
+        }
+
+        let resp: SlackResponse = resp.json().await.map_err(|e| {
+            METRICS.slack_failure(method.name(), "invalid_response");
+            Failure::Permanent(e.into())
+        })?;
 
         if !resp.ok {
-            return Err(anyhow!(
-                "slack chat.update failed: {}",
-                resp.error.unwrap_or_else(|| "unknown error".into())
-            ));
+            let error = resp.error.unwrap_or_else(|| "unknown error".into());
+            METRICS.slack_failure(method.name(), &error);
+            let err = anyhow::Error::new(SlackError { method: method.name(), code: error.clone() });
+            return Err(match error.as_str() {
+                "ratelimited" => Failure::RateLimited(retry_after),
+                "internal_error" | "fatal_error" | "service_unavailable" | "request_timeout" => {
+                    Failure::Transient(err)
+                }
+                _ => Failure::Permanent(err),
+            });
         }
 
-        Ok(())
+        Ok(resp)
+    }
+}
+
+/// Exponential backoff from one second, with up to 50% jitter so retries after an
+/// outage don't arrive in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let base = Duration::from_secs(1 << attempt.min(6));
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let jitter = base.mul_f64(f64::from(nanos % 500) / 1000.0);
+    base + jitter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(ts: &str, text: &str) -> (Call, oneshot::Receiver<Result<SlackResponse>>) {
+        let (reply, rx) = oneshot::channel();
+        let call = Call {
+            method: Method::Update,
+            body: serde_json::json!({ "channel": "C1", "ts": ts, "text": text }),
+            reply,
+            attempts: 0,
+            rate_limited: 0,
+            not_before: Instant::now(),
+        };
+        (call, rx)
+    }
+
+    #[test]
+    fn errors_are_matched_on_their_code() {
+        let e = anyhow::Error::new(SlackError { method: "reactions.add", code: "already_reacted".into() });
+        assert!(SlackError::is(&e, "already_reacted"));
+        assert_eq!(e.to_string(), "slack reactions.add failed: already_reacted");
+        assert!(!SlackError::is(&anyhow!("not_already_reacted"), "already_reacted"));
+    }
+
+    #[tokio::test]
+    async fn queued_updates_for_same_message_are_coalesced() {
+        let mut queue = Queue::default();
+        let (first, mut first_rx) = update("1.1", "x1");
+        let (other, _other_rx) = update("2.2", "y1");
+        let (second, _second_rx) = update("1.1", "x2");
+        queue.push(first);
+        queue.push(other);
+        queue.push(second);
+
+        assert_eq!(queue.calls.len(), 2);
+        assert_eq!(queue.calls[0].body["text"], "x2");
+        assert!(first_rx.try_recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn rate_limited_calls_give_up_eventually() {
+        let (mut call, _rx) = update("1.1", "a");
+        let retries = std::iter::repeat_with(|| call.rate_limited()).take_while(|&retry| retry).count();
+        assert_eq!(retries as u32, MAX_RATE_LIMITED - 1);
+    }
+
+    #[tokio::test]
+    async fn calls_of_same_method_are_spaced_out() {
+        let mut queue = Queue::default();
+        let (a, _a_rx) = update("1.1", "a");
+        let (b, _b_rx) = update("2.2", "b");
+        queue.push(a);
+        queue.push(b);
+        let now = Instant::now();
+
+        assert!(queue.pop_ready(now).is_ok());
+        let wake_at = queue.pop_ready(now).err().flatten().unwrap();
+        assert_eq!(wake_at, now + Method::Update.min_interval());
     }
 }
@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::{Api, ObjectMeta, Patch, PatchParams};
+use serde::{Deserialize, Serialize};
+
+use crate::model::Log;
+use crate::notifier::PostedMessage;
+use crate::silence::Silence;
+
+const CONFIGMAP_KEY: &str = "state.json";
+/// ConfigMaps are capped at 1 MiB, metadata included.
+const CONFIGMAP_MAX_BYTES: usize = 900 * 1024;
+/// Longest message and stack trace kept of a stored sample.
+const SAMPLE_TEXT_MAX: usize = 8 * 1024;
+
+/// Everything that has to survive a restart.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Snapshot {
+    pub aggregates: Vec<StoredAggregate>,
+    pub silences: Vec<Silence>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredAggregate {
+    pub key: String,
+    pub namespace: String,
+    pub container: String,
+    pub count: u32,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub sample: Log,
+    pub pods: HashSet<String>,
+    pub trace_ids: HashSet<String>,
+    pub posted: Vec<PostedMessage>,
+    /// Channels the alert should be in but could not be posted to yet.
+    #[serde(default)]
+    pub unposted: Vec<String>,
+    /// Hashes of the distinct messages already posted to the thread.
+    #[serde(default)]
+    pub variants: HashSet<u64>,
+    /// Occurrences a threshold rule held back before the first post.
+    #[serde(default)]
+    pub buffered: u32,
+    /// Who acknowledged the alert from Slack.
+    #[serde(default)]
+    pub acked_by: Option<String>,
+    /// Who muted the alert from Slack, and until when.
+    #[serde(default)]
+    pub muted: Option<(String, DateTime<Utc>)>,
+}
+
+/// Serialize `snapshot` in at most `max_bytes`. Samples are cut down first, then the least
+/// recently seen aggregates are left out, so a large incident can't stop state from being
+/// saved at all.
+fn encode(snapshot: &Snapshot, max_bytes: usize) -> Result<String> {
+    let mut aggregates = snapshot.aggregates.clone();
+    for agg in &mut aggregates {
+        agg.sample.truncate_text(SAMPLE_TEXT_MAX);
+    }
+    aggregates.sort_by_key(|a| std::cmp::Reverse(a.last_seen));
+
+    let mut kept = Snapshot { aggregates: Vec::new(), silences: snapshot.silences.clone() };
+    let mut size = serde_json::to_string(&kept)?.len();
+    if size > max_bytes {
+        bail!("{} silences take {} bytes, more than the {} allowed", kept.silences.len(), size, max_bytes);
+    }
+    let mut dropped = 0;
+    for agg in aggregates {
+        // Plus one for the separating comma.
+        let len = serde_json::to_string(&agg)?.len() + 1;
+        if size + len > max_bytes {
+            dropped += 1;
+            continue;
+        }
+        size += len;
+        kept.aggregates.push(agg);
+    }
+    if dropped > 0 {
+        log::warn!("state is over {} bytes, left out the {} least recently seen aggregates", max_bytes, dropped);
+    }
+    Ok(serde_json::to_string(&kept)?)
+}
+
+pub trait StateStore: Send + Sync {
+    fn load(&self) -> BoxFuture<'_, Result<Snapshot>>;
+    fn save<'a>(&'a self, snapshot: &'a Snapshot) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Pick a backend from `STATE_BACKEND` (`file`, `configmap` or `none`).
+pub fn from_env(client: &kube::Client) -> Option<Box<dyn StateStore>> {
+    match std::env::var("STATE_BACKEND").unwrap_or_default().as_str() {
+        "file" => {
+            let path = std::env::var("STATE_FILE").unwrap_or_else(|_| "/tmp/logs-state.json".into());
+            Some(Box::new(FileStore { path: path.into() }))
+        }
+        "configmap" => {
+            let name = std::env::var("STATE_CONFIGMAP").unwrap_or_else(|_| "logs-state".into());
+            let namespace = crate::env("NAIS_NAMESPACE");
+            Some(Box::new(ConfigMapStore {
+                api: Api::namespaced(client.clone(), &namespace),
+                name,
+            }))
+        }
+        "" | "none" => None,
+        other => {
+            log::warn!("unknown STATE_BACKEND {}, state will not be persisted", other);
+            None
+        }
+    }
+}
+
+/// Snapshot as a JSON file on local disk, e.g. a mounted volume.
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl StateStore for FileStore {
+    fn load(&self) -> BoxFuture<'_, Result<Snapshot>> {
+        Box::pin(async move {
+            match tokio::fs::read(&self.path).await {
+                Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Snapshot::default()),
+                Err(e) => Err(e).context(format!("failed to read {}", self.path.display())),
+            }
+        })
+    }
+
+    fn save<'a>(&'a self, snapshot: &'a Snapshot) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // Write next to the target and rename so a crash never leaves a torn file.
+            let tmp = self.path.with_extension("tmp");
+            tokio::fs::write(&tmp, encode(snapshot, usize::MAX)?).await?;
+            tokio::fs::rename(&tmp, &self.path)
+                .await
+                .context(format!("failed to write {}", self.path.display()))
+        })
+    }
+}
+
+/// Snapshot as JSON in a ConfigMap in our own namespace, created on first save.
+pub struct ConfigMapStore {
+    api: Api<ConfigMap>,
+    name: String,
+}
+
+impl StateStore for ConfigMapStore {
+    fn load(&self) -> BoxFuture<'_, Result<Snapshot>> {
+        Box::pin(async move {
+            let Some(cm) = self.api.get_opt(&self.name).await? else {
+                return Ok(Snapshot::default());
+            };
+            match cm.data.as_ref().and_then(|d| d.get(CONFIGMAP_KEY)) {
+                Some(json) => Ok(serde_json::from_str(json)?),
+                None => Ok(Snapshot::default()),
+            }
+        })
+    }
+
+    fn save<'a>(&'a self, snapshot: &'a Snapshot) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let cm = ConfigMap {
+                metadata: ObjectMeta {
+                    name: Some(self.name.clone()),
+                    ..ObjectMeta::default()
+                },
+                data: Some(BTreeMap::from([(
+                    CONFIGMAP_KEY.to_string(),
+                    encode(snapshot, CONFIGMAP_MAX_BYTES)?,
+                )])),
+                ..ConfigMap::default()
+            };
+            self.api
+                .patch(&self.name, &PatchParams::apply("logs").force(), &Patch::Apply(&cm))
+                .await
+                .context(format!("failed to apply configmap {}", self.name))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_store_round_trip() {
+        let path = std::env::temp_dir().join(format!("logs-state-{}.json", std::process::id()));
+        let store = FileStore { path: path.clone() };
+        assert!(store.load().await.unwrap().aggregates.is_empty());
+
+        let sample: Log =
+            serde_json::from_str(r#"{"level":"ERROR","@timestamp":"2025-05-19T08:00:00Z","message":"boom"}"#)
+                .unwrap();
+        let snapshot = Snapshot {
+            aggregates: vec![StoredAggregate {
+                key: "ns/app||1".into(),
+                namespace: "ns".into(),
+                container: "app".into(),
+                count: 3,
+                first_seen: Utc::now(),
+                last_seen: Utc::now(),
+                sample,
+                pods: HashSet::from(["app-1".to_string()]),
+                trace_ids: HashSet::new(),
+                posted: vec![PostedMessage { channel: "C1".into(), ts: "1.2".into() }],
+                unposted: vec!["C2".into()],
+                variants: HashSet::new(),
+                buffered: 0,
+                acked_by: Some("kari".into()),
+                muted: None,
+            }],
+            silences: vec![serde_json::from_value(serde_json::json!({
+                "id": "s1",
+                "container": "app",
+                "created_by": "kari",
+                "created_at": "2025-05-19T08:00:00Z",
+                "expires_at": "2025-05-19T09:00:00Z",
+                "suppressed": { "ns/app||1": 4 },
+            }))
+            .unwrap()],
+        };
+        store.save(&snapshot).await.unwrap();
+
+        let loaded = store.load().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.aggregates.len(), 1);
+        assert_eq!(loaded.aggregates[0].count, 3);
+        assert_eq!(loaded.aggregates[0].posted[0].ts, "1.2");
+        assert_eq!(loaded.aggregates[0].unposted, vec!["C2"]);
+        assert_eq!(loaded.aggregates[0].acked_by.as_deref(), Some("kari"));
+        assert_eq!(loaded.silences[0].matchers.container.as_deref(), Some("app"));
+        assert_eq!(loaded.silences[0].suppressed["ns/app||1"], 4);
+    }
+
+    #[test]
+    fn incident_sized_state_still_fits_in_a_configmap() {
+        let stack_trace = "\tat no.nav.Foo.bar(Foo.kt:1)\n".repeat(2500);
+        let aggregates: Vec<StoredAggregate> = (0..100)
+            .map(|i| StoredAggregate {
+                key: format!("ns/app||{i}"),
+                namespace: "ns".into(),
+                container: "app".into(),
+                count: 10_000,
+                first_seen: Utc::now(),
+                last_seen: Utc::now() - chrono::Duration::seconds(i),
+                sample: serde_json::from_value(serde_json::json!({
+                    "level": "ERROR", "message": "boom ".repeat(20_000), "stack_trace": stack_trace,
+                }))
+                .unwrap(),
+                pods: HashSet::from([format!("app-{i}")]),
+                trace_ids: (0..100).map(|t| format!("{t:032x}")).collect(),
+                posted: vec![PostedMessage { channel: "C1".into(), ts: format!("{i}.0") }],
+                unposted: Vec::new(),
+                variants: HashSet::new(),
+                buffered: 0,
+                acked_by: None,
+                muted: None,
+            })
+            .collect();
+        let snapshot = Snapshot { aggregates, silences: Vec::new() };
+
+        let json = encode(&snapshot, CONFIGMAP_MAX_BYTES).unwrap();
+        assert!(json.len() <= CONFIGMAP_MAX_BYTES);
+        let loaded: Snapshot = serde_json::from_str(&json).unwrap();
+        assert!(!loaded.aggregates.is_empty());
+        assert_eq!(loaded.aggregates[0].key, "ns/app||0");
+        assert!(loaded.aggregates[0].sample.message().len() <= SAMPLE_TEXT_MAX + 3);
+    }
+}
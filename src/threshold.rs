@@ -0,0 +1,92 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::model::Log;
+use crate::routing::{anchored, unanchored};
+
+/// Longest window a rule can hold alerts back for.
+const MAX_MINUTES: i64 = 7 * 24 * 60;
+
+/// One entry in `THRESHOLD_RULES`. Matching errors are held back until `count` of them
+/// occurred within `minutes`, then posted as usual. Matchers work like in `SLACK_ROUTES`.
+#[derive(Deserialize, Debug)]
+pub struct Rule {
+    #[serde(default, deserialize_with = "anchored")]
+    pub namespace: Option<Regex>,
+    #[serde(default, deserialize_with = "anchored")]
+    pub container: Option<Regex>,
+    #[serde(default, deserialize_with = "anchored")]
+    pub logger: Option<Regex>,
+    /// Unanchored, matches anywhere in the message.
+    #[serde(default, deserialize_with = "unanchored")]
+    pub message: Option<Regex>,
+    pub count: u32,
+    pub minutes: i64,
+}
+
+/// Decides whether a new aggregate is posted right away or buffered first.
+#[derive(Default)]
+pub struct Thresholds {
+    rules: Vec<Rule>,
+}
+
+impl Thresholds {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Thresholds { rules }
+    }
+
+    /// Rules from `THRESHOLD_RULES`, a JSON array.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("THRESHOLD_RULES") {
+            Ok(json) if !json.trim().is_empty() => Self::parse(&json).context("invalid THRESHOLD_RULES"),
+            _ => Ok(Thresholds::default()),
+        }
+    }
+
+    fn parse(json: &str) -> Result<Self> {
+        let rules: Vec<Rule> = serde_json::from_str(json)?;
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.count < 1 {
+                bail!("rule {i}: count must be at least 1");
+            }
+            if !(1..=MAX_MINUTES).contains(&rule.minutes) {
+                bail!("rule {i}: minutes must be between 1 and {MAX_MINUTES}");
+            }
+        }
+        Ok(Thresholds::new(rules))
+    }
+
+    /// The first rule matching the entry, if any.
+    pub fn rule_for(&self, namespace: &str, container: &str, log: &Log) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(namespace, container, log))
+    }
+}
+
+impl Rule {
+    fn matches(&self, namespace: &str, container: &str, log: &Log) -> bool {
+        let is = |matcher: &Option<Regex>, value: &str| matcher.as_ref().is_none_or(|re| re.is_match(value));
+        is(&self.namespace, namespace)
+            && is(&self.container, container)
+            && is(&self.logger, log.logger_name().unwrap_or(""))
+            && is(&self.message, log.message())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_need_a_positive_count_and_window() {
+        assert!(Thresholds::parse(r#"[{ "count": 3, "minutes": 10 }]"#).is_ok());
+        for json in [
+            r#"[{ "count": 0, "minutes": 10 }]"#,
+            r#"[{ "count": 3, "minutes": 0 }]"#,
+            r#"[{ "count": 3, "minutes": -5 }]"#,
+            r#"[{ "count": 3, "minutes": 9223372036854775807 }]"#,
+        ] {
+            assert!(Thresholds::parse(json).is_err(), "{json}");
+        }
+    }
+}